@@ -16,6 +16,8 @@ export function useWebSocketGame() {
 		handlePlayerDisconnect,
 		handlePlayerReconnect,
 		handleGameState,
+		handleJoinAck,
+		handleServerError,
 		cleanupDuplicatePlayers,
 	} = useGameStore();
 
@@ -25,12 +27,26 @@ export function useWebSocketGame() {
 		readyState: wsReadyState,
 		getWebSocket,
 	} = useWebSocket("ws://192.168.1.12:8000/ws", {
-		onOpen: () => {
+		onOpen: (event) => {
 			console.log("Grid game WebSocket connected");
 			console.log("Player ID:", playerId);
 			console.log("Player Color:", playerColor);
 			setConnected(true);
-			setShowNameModal(true);
+
+			// A returning player takes their token back with the secret the
+			// server gave them on join; everyone else picks a name first
+			const reconnectToken = localStorage.getItem("reconnectToken");
+			if (reconnectToken) {
+				console.log("Reconnecting with stored reconnect token");
+				(event.target as WebSocket).send(
+					JSON.stringify({
+						type: "player_reconnect",
+						reconnect_token: reconnectToken,
+					}),
+				);
+			} else {
+				setShowNameModal(true);
+			}
 		},
 		onMessage: (event) => {
 			console.log("=== SERVER MESSAGE RECEIVED ===");
@@ -79,6 +95,12 @@ export function useWebSocketGame() {
 						case "client_disconnected":
 							handlePlayerDisconnect(data);
 							break;
+						case "join_ack":
+							handleJoinAck(data);
+							break;
+						case "error":
+							handleServerError(data);
+							break;
 						default:
 							console.log(
 								"Unknown message type:",
@@ -150,7 +172,6 @@ export function useWebSocketGame() {
 				player_id: playerId,
 				player_name: playerName.trim(),
 				color: playerColor,
-				position: { x: 20, y: 15 },
 			};
 			console.log("Sending join message with name:", joinMessage);
 			sendMessage(JSON.stringify(joinMessage));
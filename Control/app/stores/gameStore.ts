@@ -71,6 +71,8 @@ interface GameActions {
 	handlePlayerDisconnect: (data: Record<string, unknown>) => void;
 	handlePlayerReconnect: (data: Record<string, unknown>) => void;
 	handleGameState: (data: Record<string, unknown>) => void;
+	handleJoinAck: (data: Record<string, unknown>) => void;
+	handleServerError: (data: Record<string, unknown>) => void;
 }
 
 // Generate a random color for each player
@@ -331,6 +333,7 @@ export const useGameStore = create<GameState & GameActions>((set, get) => ({
 		localStorage.removeItem("playerId");
 		localStorage.removeItem("playerColor");
 		localStorage.removeItem("playerName");
+		localStorage.removeItem("reconnectToken");
 
 		// Clear store state
 		set({
@@ -568,6 +571,46 @@ export const useGameStore = create<GameState & GameActions>((set, get) => ({
 		}
 	},
 
+	// The server accepted our join or reconnect; keep the secret token that
+	// lets this browser take the player back after a reload or disconnect
+	handleJoinAck: (data: Record<string, unknown>) => {
+		const playerId = String(data.player_id || "");
+		const reconnectToken = String(data.reconnect_token || "");
+		console.log("🎮 Store: Joined as", playerId, "with role", data.role);
+
+		if (reconnectToken) {
+			localStorage.setItem("reconnectToken", reconnectToken);
+		}
+		if (playerId) {
+			localStorage.setItem("playerId", playerId);
+			set({ playerId, showNameModal: false });
+			get().syncCurrentPlayer();
+		}
+	},
+
+	handleServerError: (data: Record<string, unknown>) => {
+		const code = String(data.code || "");
+		console.error("❌ Store: Server error:", code, data.message);
+
+		switch (code) {
+			case "invalid_reconnect_token":
+				// The player was removed or the server lost its state; join afresh
+				localStorage.removeItem("reconnectToken");
+				set({ showNameModal: true });
+				break;
+			case "player_id_taken": {
+				// Someone else registered this id; pick a new one and try again
+				const id = `player_${Date.now()}_${Math.random().toString(36).substr(2, 9)}`;
+				localStorage.setItem("playerId", id);
+				set({ playerId: id, showNameModal: true });
+				break;
+			}
+			case "name_taken":
+				set({ showNameModal: true });
+				break;
+		}
+	},
+
 	handleGameState: (data: Record<string, unknown>) => {
 		if (data.data && typeof data.data === "object") {
 			console.log("🎮 Store: Setting game state:", data.data);
@@ -0,0 +1,82 @@
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::ClientSession;
+
+/// Longest chat message accepted, in characters.
+pub const MAX_CHAT_LEN: usize = 500;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ChatChannel {
+    /// Everyone in the room.
+    #[default]
+    Public,
+    /// Only the sender and the named recipient.
+    Whisper,
+    /// Only the sender and the game master.
+    Gm,
+    /// Lines generated by the server; clients cannot send on this channel.
+    System,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChatMessage {
+    pub id: String,
+    pub channel: ChatChannel,
+    /// Absent on system lines.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub from_player_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub from_name: Option<String>,
+    /// The whisper's recipient.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub to_player_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub to_name: Option<String>,
+    pub text: String,
+    /// Seconds since the Unix epoch.
+    pub sent_at: u64,
+}
+
+impl ChatMessage {
+    pub fn system(text: String, sent_at: u64) -> Self {
+        Self {
+            id: Uuid::new_v4().simple().to_string(),
+            channel: ChatChannel::System,
+            from_player_id: None,
+            from_name: None,
+            to_player_id: None,
+            to_name: None,
+            text,
+            sent_at,
+        }
+    }
+
+    /// Whether a connection bound to `session` (or not joined) may read this message.
+    pub fn is_visible_to(&self, session: Option<&ClientSession>) -> bool {
+        match self.channel {
+            ChatChannel::Public | ChatChannel::System => true,
+            ChatChannel::Whisper => session.is_some_and(|session| {
+                self.from_player_id.as_ref() == Some(&session.player_id) || self.to_player_id.as_ref() == Some(&session.player_id)
+            }),
+            ChatChannel::Gm => session.is_some_and(|session| {
+                session.is_game_master() || self.from_player_id.as_ref() == Some(&session.player_id)
+            }),
+        }
+    }
+}
+
+/// Strips control characters (other than newlines) and surrounding whitespace,
+/// rejecting messages that end up empty or too long.
+pub fn sanitize(text: &str) -> Result<String, String> {
+    let cleaned: String = text.chars().filter(|c| !c.is_control() || *c == '\n').collect();
+    let cleaned = cleaned.trim();
+    if cleaned.is_empty() {
+        return Err("Chat messages cannot be empty".to_string());
+    }
+    if cleaned.chars().count() > MAX_CHAT_LEN {
+        return Err(format!("Chat messages are limited to {} characters", MAX_CHAT_LEN));
+    }
+    Ok(cleaned.to_string())
+}
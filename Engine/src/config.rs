@@ -0,0 +1,587 @@
+use clap::{Args, Parser, Subcommand};
+use log::LevelFilter;
+use serde::Deserialize;
+use std::convert::Infallible;
+use std::env;
+use std::fmt::Display;
+use std::fs;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+use crate::limits::RateLimit;
+use crate::map::MapDefinition;
+use crate::origin;
+use crate::outbound::OverflowPolicy;
+use crate::pathfinding::{DiagonalRule, PassThrough};
+use crate::replay::ReplayArgs;
+
+const DEFAULT_BIND: &str = "0.0.0.0:8000";
+const DEFAULT_MAX_MESSAGE_SIZE: usize = 64 * 1024;
+const DEFAULT_MAX_ROOMS: usize = 100;
+const DEFAULT_ROOM_IDLE_TIMEOUT_SECS: u64 = 24 * 60 * 60;
+const DEFAULT_RATE_LIMIT_MESSAGES_PER_SEC: u32 = 20;
+const DEFAULT_RATE_LIMIT_BURST: u32 = 40;
+const DEFAULT_RATE_LIMIT_STRIKES: u32 = 3;
+const DEFAULT_STATE_FILE: &str = "game_state.json";
+const DEFAULT_EVENT_LOG: &str = "game_events.jsonl";
+const DEFAULT_SNAPSHOT_INTERVAL_SECS: u64 = 30;
+const DEFAULT_OUTBOUND_QUEUE_CAPACITY: usize = 256;
+const DEFAULT_HEARTBEAT_INTERVAL_SECS: u64 = 15;
+const DEFAULT_PONG_TIMEOUT_SECS: u64 = 45;
+const DEFAULT_SHUTDOWN_TIMEOUT_SECS: u64 = 10;
+const DEFAULT_SIGHT_RADIUS: i32 = 8;
+
+/// Command line of the `warp-drive` binary.
+#[derive(Debug, Parser)]
+#[command(version, about = "WebSocket server for a shared tabletop grid")]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+    /// TOML file to read settings from; CONFIG_FILE in the environment
+    #[arg(long, short, value_name = "FILE")]
+    pub config: Option<PathBuf>,
+    /// Address to listen on, same as --bind
+    #[arg(value_name = "ADDR", conflicts_with = "bind")]
+    pub addr: Option<SocketAddr>,
+    #[command(flatten)]
+    pub settings: Settings,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Rebuild game state from an event log and print it as a snapshot
+    Replay(ReplayArgs),
+}
+
+/// Settings from one source: command line flags, the environment or the
+/// config file. Whatever a source leaves out falls through to the next one.
+#[derive(Debug, Default, Args, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Settings {
+    /// Address to listen on [default: 0.0.0.0:8000]
+    #[arg(long, value_name = "ADDR")]
+    pub bind: Option<SocketAddr>,
+    /// Browser origin allowed to connect, repeatable or comma separated; * allows any [default: none]
+    #[arg(long = "allowed-origin", value_name = "ORIGIN", value_delimiter = ',')]
+    pub allowed_origins: Option<Vec<String>>,
+    /// Most WebSocket connections open at once [default: unlimited]
+    #[arg(long, value_name = "N")]
+    pub max_clients: Option<usize>,
+    /// Most rooms open at once [default: 100]
+    #[arg(long, value_name = "N")]
+    pub max_rooms: Option<usize>,
+    /// How long a room may go without connected clients before it is closed [default: 86400]
+    #[arg(long, value_name = "SECS")]
+    pub room_idle_timeout_secs: Option<u64>,
+    /// Largest message a client may send, in bytes [default: 65536]
+    #[arg(long, value_name = "BYTES")]
+    pub max_message_size: Option<usize>,
+    /// Largest WebSocket frame a client may send, in bytes [default: max_message_size]
+    #[arg(long, value_name = "BYTES")]
+    pub max_frame_size: Option<usize>,
+    /// Messages per second each connection may keep sending [default: 20]
+    #[arg(long, value_name = "N")]
+    pub rate_limit_messages_per_sec: Option<u32>,
+    /// Messages each connection may send in a burst [default: 40]
+    #[arg(long, value_name = "N")]
+    pub rate_limit_burst: Option<u32>,
+    /// Rate limit warnings a client may get before going over again disconnects it [default: 3]
+    #[arg(long, value_name = "N")]
+    pub rate_limit_strikes: Option<u32>,
+    /// JSON map that new rooms start on; empty uses a blank 40x25 grid
+    #[arg(long, value_name = "FILE", value_parser = path_arg)]
+    pub map_file: Option<PathBuf>,
+    /// Where game state is snapshotted; empty disables persistence [default: game_state.json]
+    #[arg(long, value_name = "FILE", value_parser = path_arg)]
+    pub state_file: Option<PathBuf>,
+    /// How often game state is snapshotted [default: 30]
+    #[arg(long, value_name = "SECS")]
+    pub snapshot_interval_secs: Option<u64>,
+    /// Where game state changes are logged; empty disables the log [default: game_events.jsonl]
+    #[arg(long, value_name = "FILE", value_parser = path_arg)]
+    pub event_log: Option<PathBuf>,
+    /// How often each client is pinged [default: 15]
+    #[arg(long, value_name = "SECS")]
+    pub heartbeat_interval_secs: Option<u64>,
+    /// How long a client may stay silent before it is disconnected [default: 45]
+    #[arg(long, value_name = "SECS")]
+    pub pong_timeout_secs: Option<u64>,
+    /// How long shutdown waits for clients to disconnect [default: 10]
+    #[arg(long, value_name = "SECS")]
+    pub shutdown_timeout_secs: Option<u64>,
+    /// off, error, warn, info, debug or trace; RUST_LOG takes precedence [default: info]
+    #[arg(long, value_name = "LEVEL")]
+    pub log_level: Option<String>,
+    /// Kept off the command line so it does not show up in process listings.
+    #[arg(skip)]
+    pub gm_secret: Option<String>,
+    /// Messages buffered per client before the overflow policy applies [default: 256]
+    #[arg(long, value_name = "N")]
+    pub outbound_queue_capacity: Option<usize>,
+    /// disconnect or drop_oldest [default: disconnect]
+    #[arg(long, value_name = "POLICY")]
+    pub outbound_queue_policy: Option<String>,
+    /// Limit what players learn about other tokens to what they can see [default: false]
+    #[arg(long, value_name = "BOOL")]
+    pub fog_of_war: Option<bool>,
+    /// How many cells a player can see under fog of war [default: 8]
+    #[arg(long, value_name = "CELLS")]
+    pub sight_radius: Option<i32>,
+    /// Squares each token may move per encounter turn [default: unlimited]
+    #[arg(long, value_name = "SQUARES")]
+    pub movement_budget: Option<u32>,
+    /// 5e or 5-10-5 [default: 5e]
+    #[arg(long, value_name = "RULE")]
+    pub diagonal_rule: Option<String>,
+    /// Let tokens move through tokens on their own side [default: true]
+    #[arg(long, value_name = "BOOL")]
+    pub pass_through_allies: Option<bool>,
+    /// Let tokens move through tokens on the other side [default: false]
+    #[arg(long, value_name = "BOOL")]
+    pub pass_through_enemies: Option<bool>,
+}
+
+impl Settings {
+    /// Reads a TOML config file. Keys are the setting names, e.g. `max_clients = 20`.
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let contents = fs::read_to_string(path).map_err(|e| format!("Failed to read config file {}: {}", path.display(), e))?;
+        toml::from_str(&contents).map_err(|e| format!("Invalid config file {}: {}", path.display(), e))
+    }
+
+    /// Reads the settings' upper-case environment variables, e.g. `MAX_CLIENTS`,
+    /// plus `BIND_ADDR` for `bind`. Empty values count as unset, except for
+    /// files and the game master secret, where they disable the feature.
+    pub fn from_env() -> Result<Self, String> {
+        Ok(Self {
+            bind: env_parse("BIND_ADDR")?,
+            allowed_origins: env_nonempty("ALLOWED_ORIGINS").map(|origins| origins.split(',').map(|origin| origin.trim().to_string()).collect()),
+            max_clients: env_parse("MAX_CLIENTS")?,
+            max_rooms: env_parse("MAX_ROOMS")?,
+            room_idle_timeout_secs: env_parse("ROOM_IDLE_TIMEOUT_SECS")?,
+            max_message_size: env_parse("MAX_MESSAGE_SIZE")?,
+            max_frame_size: env_parse("MAX_FRAME_SIZE")?,
+            rate_limit_messages_per_sec: env_parse("RATE_LIMIT_MESSAGES_PER_SEC")?,
+            rate_limit_burst: env_parse("RATE_LIMIT_BURST")?,
+            rate_limit_strikes: env_parse("RATE_LIMIT_STRIKES")?,
+            map_file: env::var_os("MAP_FILE").map(PathBuf::from),
+            state_file: env::var_os("STATE_FILE").map(PathBuf::from),
+            snapshot_interval_secs: env_parse("SNAPSHOT_INTERVAL_SECS")?,
+            event_log: env::var_os("EVENT_LOG").map(PathBuf::from),
+            heartbeat_interval_secs: env_parse("HEARTBEAT_INTERVAL_SECS")?,
+            pong_timeout_secs: env_parse("PONG_TIMEOUT_SECS")?,
+            shutdown_timeout_secs: env_parse("SHUTDOWN_TIMEOUT_SECS")?,
+            log_level: env_nonempty("LOG_LEVEL"),
+            gm_secret: env::var("GM_SECRET").ok(),
+            outbound_queue_capacity: env_parse("OUTBOUND_QUEUE_CAPACITY")?,
+            outbound_queue_policy: env_nonempty("OUTBOUND_QUEUE_POLICY"),
+            fog_of_war: env_parse("FOG_OF_WAR")?,
+            sight_radius: env_parse("SIGHT_RADIUS")?,
+            movement_budget: env_parse("MOVEMENT_BUDGET")?,
+            diagonal_rule: env_nonempty("DIAGONAL_RULE"),
+            pass_through_allies: env_parse("PASS_THROUGH_ALLIES")?,
+            pass_through_enemies: env_parse("PASS_THROUGH_ENEMIES")?,
+        })
+    }
+
+    /// Fills in whatever `self` leaves out from `fallback`.
+    pub fn or(self, fallback: Settings) -> Settings {
+        Settings {
+            bind: self.bind.or(fallback.bind),
+            allowed_origins: self.allowed_origins.or(fallback.allowed_origins),
+            max_clients: self.max_clients.or(fallback.max_clients),
+            max_rooms: self.max_rooms.or(fallback.max_rooms),
+            room_idle_timeout_secs: self.room_idle_timeout_secs.or(fallback.room_idle_timeout_secs),
+            max_message_size: self.max_message_size.or(fallback.max_message_size),
+            max_frame_size: self.max_frame_size.or(fallback.max_frame_size),
+            rate_limit_messages_per_sec: self.rate_limit_messages_per_sec.or(fallback.rate_limit_messages_per_sec),
+            rate_limit_burst: self.rate_limit_burst.or(fallback.rate_limit_burst),
+            rate_limit_strikes: self.rate_limit_strikes.or(fallback.rate_limit_strikes),
+            map_file: self.map_file.or(fallback.map_file),
+            state_file: self.state_file.or(fallback.state_file),
+            snapshot_interval_secs: self.snapshot_interval_secs.or(fallback.snapshot_interval_secs),
+            event_log: self.event_log.or(fallback.event_log),
+            heartbeat_interval_secs: self.heartbeat_interval_secs.or(fallback.heartbeat_interval_secs),
+            pong_timeout_secs: self.pong_timeout_secs.or(fallback.pong_timeout_secs),
+            shutdown_timeout_secs: self.shutdown_timeout_secs.or(fallback.shutdown_timeout_secs),
+            log_level: self.log_level.or(fallback.log_level),
+            gm_secret: self.gm_secret.or(fallback.gm_secret),
+            outbound_queue_capacity: self.outbound_queue_capacity.or(fallback.outbound_queue_capacity),
+            outbound_queue_policy: self.outbound_queue_policy.or(fallback.outbound_queue_policy),
+            fog_of_war: self.fog_of_war.or(fallback.fog_of_war),
+            sight_radius: self.sight_radius.or(fallback.sight_radius),
+            movement_budget: self.movement_budget.or(fallback.movement_budget),
+            diagonal_rule: self.diagonal_rule.or(fallback.diagonal_rule),
+            pass_through_allies: self.pass_through_allies.or(fallback.pass_through_allies),
+            pass_through_enemies: self.pass_through_enemies.or(fallback.pass_through_enemies),
+        }
+    }
+}
+
+/// Server settings, resolved and validated at startup.
+#[derive(Debug, Clone)]
+pub struct ServerConfig {
+    pub bind: SocketAddr,
+    /// Browser origins allowed to connect, in lower case; `*` allows any.
+    pub allowed_origins: Vec<String>,
+    /// Most WebSocket connections open at once; `None` is unlimited.
+    pub max_clients: Option<usize>,
+    /// Most rooms open at once; clients asking for a new room beyond it are refused.
+    pub max_rooms: usize,
+    /// How long a room may go without connected clients before it is closed,
+    /// even with players registered.
+    pub room_idle_timeout: Duration,
+    /// Largest message a client may send, in bytes.
+    pub max_message_size: usize,
+    /// Largest WebSocket frame a client may send, in bytes.
+    pub max_frame_size: usize,
+    pub rate_limit: RateLimit,
+    pub log_level: LevelFilter,
+    /// Where game state is snapshotted; `None` disables persistence.
+    pub state_file: Option<PathBuf>,
+    pub snapshot_interval: Duration,
+    /// Where every accepted game state change is appended; `None` disables the log.
+    pub event_log: Option<PathBuf>,
+    /// Secret a client must present in `player_join` to become game master; `None` disables the role.
+    pub gm_secret: Option<String>,
+    /// Messages buffered per client before `outbound_overflow_policy` applies.
+    pub outbound_queue_capacity: usize,
+    pub outbound_overflow_policy: OverflowPolicy,
+    /// How often the server pings each client.
+    pub heartbeat_interval: Duration,
+    /// How long a client may stay silent before it is disconnected.
+    pub pong_timeout: Duration,
+    /// How long shutdown waits for clients to disconnect before exiting.
+    pub shutdown_timeout: Duration,
+    /// Filters what each player learns about other tokens by line of sight.
+    pub fog_of_war: bool,
+    /// How many cells a player can see under fog of war.
+    pub sight_radius: i32,
+    /// The map new rooms start on.
+    pub map: MapDefinition,
+    /// Squares each token may move per encounter turn; `None` is unlimited.
+    pub movement_budget: Option<u32>,
+    pub diagonal_rule: DiagonalRule,
+    /// Whether moving tokens may walk through allied and enemy tokens.
+    pub pass_through: PassThrough,
+}
+
+impl ServerConfig {
+    /// Resolves the server settings from the command line, then the
+    /// environment, then the config file named by `--config` or `CONFIG_FILE`.
+    pub fn load(cli: Cli) -> Result<Self, String> {
+        let config_file = cli.config.or_else(|| env::var_os("CONFIG_FILE").filter(|path| !path.is_empty()).map(PathBuf::from));
+        let file = match &config_file {
+            Some(path) => Settings::load(path)?,
+            None => Settings::default(),
+        };
+
+        let mut flags = cli.settings;
+        flags.bind = flags.bind.or(cli.addr);
+        Self::from_settings(flags.or(Settings::from_env()?).or(file))
+    }
+
+    /// Applies defaults to whatever is unset and checks that the result makes sense.
+    pub fn from_settings(settings: Settings) -> Result<Self, String> {
+        let bind = settings.bind.unwrap_or_else(|| DEFAULT_BIND.parse().expect("default bind address is valid"));
+
+        let allowed_origins: Vec<String> = settings.allowed_origins.unwrap_or_default().iter().map(|origin| origin.to_ascii_lowercase()).collect();
+        if let Some(origin) = allowed_origins.iter().find(|origin| !origin::is_valid(origin)) {
+            return Err(format!(
+                "Invalid allowed origin '{}': expected scheme://host[:port] such as https://example.com, null or *",
+                origin
+            ));
+        }
+
+        if settings.max_clients == Some(0) {
+            return Err("max_clients must be at least 1".to_string());
+        }
+        let max_message_size = settings.max_message_size.unwrap_or(DEFAULT_MAX_MESSAGE_SIZE);
+        if max_message_size == 0 {
+            return Err("max_message_size must be at least 1 byte".to_string());
+        }
+        let max_frame_size = settings.max_frame_size.unwrap_or(max_message_size);
+        if max_frame_size == 0 || max_frame_size > max_message_size {
+            return Err(format!(
+                "max_frame_size ({}) must be between 1 byte and max_message_size ({})",
+                max_frame_size, max_message_size
+            ));
+        }
+
+        let rate_limit = RateLimit {
+            messages_per_sec: settings.rate_limit_messages_per_sec.unwrap_or(DEFAULT_RATE_LIMIT_MESSAGES_PER_SEC),
+            burst: settings.rate_limit_burst.unwrap_or(DEFAULT_RATE_LIMIT_BURST),
+            strikes: settings.rate_limit_strikes.unwrap_or(DEFAULT_RATE_LIMIT_STRIKES),
+        };
+        if rate_limit.messages_per_sec == 0 {
+            return Err("rate_limit_messages_per_sec must be at least 1".to_string());
+        }
+        if rate_limit.burst == 0 {
+            return Err("rate_limit_burst must be at least 1".to_string());
+        }
+        if rate_limit.strikes == 0 {
+            return Err("rate_limit_strikes must be at least 1".to_string());
+        }
+
+        let log_level = match settings.log_level {
+            Some(level) => parse_setting("log_level", &level)?,
+            None => LevelFilter::Info,
+        };
+
+        let map = match nonempty_path(settings.map_file) {
+            Some(path) => MapDefinition::load(&path).map_err(|e| format!("Failed to load map from {}: {}", path.display(), e))?,
+            None => MapDefinition::default(),
+        };
+
+        let heartbeat_interval = settings.heartbeat_interval_secs.unwrap_or(DEFAULT_HEARTBEAT_INTERVAL_SECS);
+        let pong_timeout = settings.pong_timeout_secs.unwrap_or(DEFAULT_PONG_TIMEOUT_SECS);
+        if heartbeat_interval == 0 {
+            return Err("heartbeat_interval_secs must be at least 1".to_string());
+        }
+        if pong_timeout <= heartbeat_interval {
+            return Err(format!(
+                "pong_timeout_secs ({}) must be longer than heartbeat_interval_secs ({})",
+                pong_timeout, heartbeat_interval
+            ));
+        }
+
+        let snapshot_interval = settings.snapshot_interval_secs.unwrap_or(DEFAULT_SNAPSHOT_INTERVAL_SECS);
+        if snapshot_interval == 0 {
+            return Err("snapshot_interval_secs must be at least 1".to_string());
+        }
+        let outbound_queue_capacity = settings.outbound_queue_capacity.unwrap_or(DEFAULT_OUTBOUND_QUEUE_CAPACITY);
+        if outbound_queue_capacity == 0 {
+            return Err("outbound_queue_capacity must be at least 1".to_string());
+        }
+        let max_rooms = settings.max_rooms.unwrap_or(DEFAULT_MAX_ROOMS);
+        if max_rooms == 0 {
+            return Err("max_rooms must be at least 1".to_string());
+        }
+        let room_idle_timeout = settings.room_idle_timeout_secs.unwrap_or(DEFAULT_ROOM_IDLE_TIMEOUT_SECS);
+        if room_idle_timeout == 0 {
+            return Err("room_idle_timeout_secs must be at least 1".to_string());
+        }
+        let sight_radius = settings.sight_radius.unwrap_or(DEFAULT_SIGHT_RADIUS);
+        if sight_radius < 0 {
+            return Err("sight_radius cannot be negative".to_string());
+        }
+
+        Ok(Self {
+            bind,
+            allowed_origins,
+            max_clients: settings.max_clients,
+            max_rooms,
+            room_idle_timeout: Duration::from_secs(room_idle_timeout),
+            max_message_size,
+            max_frame_size,
+            rate_limit,
+            log_level,
+            state_file: nonempty_path(Some(settings.state_file.unwrap_or_else(|| PathBuf::from(DEFAULT_STATE_FILE)))),
+            snapshot_interval: Duration::from_secs(snapshot_interval),
+            event_log: nonempty_path(Some(settings.event_log.unwrap_or_else(|| PathBuf::from(DEFAULT_EVENT_LOG)))),
+            gm_secret: settings.gm_secret.filter(|secret| !secret.is_empty()),
+            outbound_queue_capacity,
+            outbound_overflow_policy: match settings.outbound_queue_policy {
+                Some(policy) => parse_setting("outbound_queue_policy", &policy)?,
+                None => OverflowPolicy::Disconnect,
+            },
+            heartbeat_interval: Duration::from_secs(heartbeat_interval),
+            pong_timeout: Duration::from_secs(pong_timeout),
+            shutdown_timeout: Duration::from_secs(settings.shutdown_timeout_secs.unwrap_or(DEFAULT_SHUTDOWN_TIMEOUT_SECS)),
+            fog_of_war: settings.fog_of_war.unwrap_or(false),
+            sight_radius,
+            map,
+            movement_budget: settings.movement_budget,
+            diagonal_rule: match settings.diagonal_rule {
+                Some(rule) => parse_setting("diagonal_rule", &rule)?,
+                None => DiagonalRule::Uniform,
+            },
+            pass_through: PassThrough {
+                allies: settings.pass_through_allies.unwrap_or(true),
+                enemies: settings.pass_through_enemies.unwrap_or(false),
+            },
+        })
+    }
+}
+
+/// Parses a path flag, keeping empty values that clap's own path parser rejects.
+fn path_arg(value: &str) -> Result<PathBuf, Infallible> {
+    Ok(PathBuf::from(value))
+}
+
+/// An empty path disables whatever it configures.
+fn nonempty_path(path: Option<PathBuf>) -> Option<PathBuf> {
+    path.filter(|path| !path.as_os_str().is_empty())
+}
+
+fn parse_setting<T>(name: &str, value: &str) -> Result<T, String>
+where
+    T: FromStr,
+    T::Err: Display,
+{
+    value.parse().map_err(|e| format!("Invalid value '{}' for {}: {}", value, name, e))
+}
+
+fn env_nonempty(name: &str) -> Option<String> {
+    env::var(name).ok().filter(|value| !value.is_empty())
+}
+
+fn env_parse<T>(name: &str) -> Result<Option<T>, String>
+where
+    T: FromStr,
+    T::Err: Display,
+{
+    env_nonempty(name).map(|value| parse_setting(name, &value)).transpose()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn flags(args: &[&str]) -> Settings {
+        let args = std::iter::once("warp-drive").chain(args.iter().copied());
+        Cli::try_parse_from(args).expect("valid command line").settings
+    }
+
+    fn error(settings: Settings) -> String {
+        ServerConfig::from_settings(settings).expect_err("settings should be rejected")
+    }
+
+    #[test]
+    fn unset_settings_get_their_defaults() {
+        let config = ServerConfig::from_settings(Settings::default()).unwrap();
+        assert_eq!(config.bind, DEFAULT_BIND.parse().unwrap());
+        assert!(config.allowed_origins.is_empty());
+        assert_eq!(config.max_clients, None);
+        assert_eq!(config.max_rooms, DEFAULT_MAX_ROOMS);
+        assert_eq!(config.max_message_size, DEFAULT_MAX_MESSAGE_SIZE);
+        assert_eq!(config.max_frame_size, DEFAULT_MAX_MESSAGE_SIZE);
+        assert_eq!(config.rate_limit.strikes, DEFAULT_RATE_LIMIT_STRIKES);
+        assert_eq!(config.log_level, LevelFilter::Info);
+        assert_eq!(config.state_file, Some(PathBuf::from(DEFAULT_STATE_FILE)));
+        assert_eq!(config.event_log, Some(PathBuf::from(DEFAULT_EVENT_LOG)));
+        assert_eq!(config.gm_secret, None);
+        assert_eq!(config.outbound_overflow_policy, OverflowPolicy::Disconnect);
+        assert_eq!(config.diagonal_rule, DiagonalRule::Uniform);
+        assert!(config.pass_through.allies);
+        assert!(!config.pass_through.enemies);
+    }
+
+    #[test]
+    fn flags_override_the_environment_which_overrides_the_file() {
+        let flags = flags(&["--max-clients", "10"]);
+        let env = Settings {
+            max_clients: Some(20),
+            sight_radius: Some(4),
+            ..Settings::default()
+        };
+        let file: Settings = toml::from_str("max_clients = 30\nsight_radius = 6\nheartbeat_interval_secs = 5").unwrap();
+
+        let config = ServerConfig::from_settings(flags.or(env).or(file)).unwrap();
+        assert_eq!(config.max_clients, Some(10));
+        assert_eq!(config.sight_radius, 4);
+        assert_eq!(config.heartbeat_interval, Duration::from_secs(5));
+    }
+
+    #[test]
+    fn empty_paths_disable_persistence_and_the_event_log() {
+        let config = ServerConfig::from_settings(flags(&["--state-file", "", "--event-log", ""])).unwrap();
+        assert_eq!(config.state_file, None);
+        assert_eq!(config.event_log, None);
+    }
+
+    #[test]
+    fn an_empty_path_still_overrides_later_sources() {
+        let file = Settings {
+            state_file: Some(PathBuf::from("from-file.json")),
+            ..Settings::default()
+        };
+        let config = ServerConfig::from_settings(flags(&["--state-file", ""]).or(file)).unwrap();
+        assert_eq!(config.state_file, None);
+    }
+
+    #[test]
+    fn an_empty_secret_disables_the_game_master_role() {
+        let settings = Settings {
+            gm_secret: Some(String::new()),
+            ..Settings::default()
+        };
+        assert_eq!(ServerConfig::from_settings(settings).unwrap().gm_secret, None);
+    }
+
+    #[test]
+    fn origins_are_lower_cased_and_checked() {
+        let config = ServerConfig::from_settings(flags(&["--allowed-origin", "HTTPS://Table.Example.com,null"])).unwrap();
+        assert_eq!(config.allowed_origins, ["https://table.example.com", "null"]);
+
+        assert!(error(flags(&["--allowed-origin", "table.example.com"])).contains("Invalid allowed origin"));
+    }
+
+    #[test]
+    fn named_values_are_parsed() {
+        let config = ServerConfig::from_settings(flags(&[
+            "--log-level",
+            "debug",
+            "--outbound-queue-policy",
+            "drop_oldest",
+            "--diagonal-rule",
+            "5-10-5",
+        ]))
+        .unwrap();
+        assert_eq!(config.log_level, LevelFilter::Debug);
+        assert_eq!(config.outbound_overflow_policy, OverflowPolicy::DropOldest);
+        assert_eq!(config.diagonal_rule, DiagonalRule::Alternating);
+
+        assert!(error(flags(&["--log-level", "loud"])).contains("log_level"));
+        assert!(error(flags(&["--diagonal-rule", "chess"])).contains("diagonal_rule"));
+    }
+
+    #[test]
+    fn zero_limits_are_rejected() {
+        for flag in [
+            "--max-clients",
+            "--max-rooms",
+            "--room-idle-timeout-secs",
+            "--max-message-size",
+            "--rate-limit-messages-per-sec",
+            "--rate-limit-burst",
+            "--rate-limit-strikes",
+            "--heartbeat-interval-secs",
+            "--snapshot-interval-secs",
+            "--outbound-queue-capacity",
+        ] {
+            assert!(ServerConfig::from_settings(flags(&[flag, "0"])).is_err(), "{flag} 0 was accepted");
+        }
+    }
+
+    #[test]
+    fn frames_cannot_exceed_messages() {
+        let config = ServerConfig::from_settings(flags(&["--max-message-size", "1000"])).unwrap();
+        assert_eq!(config.max_frame_size, 1000);
+
+        assert!(error(flags(&["--max-message-size", "1000", "--max-frame-size", "1001"])).contains("max_frame_size"));
+    }
+
+    #[test]
+    fn the_pong_timeout_must_outlast_the_heartbeat() {
+        assert!(error(flags(&["--heartbeat-interval-secs", "30", "--pong-timeout-secs", "30"])).contains("pong_timeout_secs"));
+    }
+
+    #[test]
+    fn a_negative_sight_radius_is_rejected() {
+        assert!(error(flags(&["--sight-radius=-1"])).contains("sight_radius"));
+    }
+
+    #[test]
+    fn a_missing_map_file_is_an_error() {
+        assert!(error(flags(&["--map-file", "/nonexistent/map.json"])).contains("Failed to load map"));
+    }
+
+    #[test]
+    fn the_address_argument_conflicts_with_bind() {
+        let cli = Cli::try_parse_from(["warp-drive", "127.0.0.1:9000"]).unwrap();
+        assert_eq!(cli.addr, Some("127.0.0.1:9000".parse().unwrap()));
+        assert!(Cli::try_parse_from(["warp-drive", "127.0.0.1:9000", "--bind", "127.0.0.1:9001"]).is_err());
+    }
+}
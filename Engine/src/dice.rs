@@ -0,0 +1,477 @@
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+
+const MAX_NOTATION_LEN: usize = 100;
+const MAX_TERMS: usize = 20;
+const MAX_DICE: u32 = 100;
+const MAX_SIDES: u32 = 1000;
+/// Exploding dice stop adding extra dice after this many per term.
+const MAX_EXPLOSIONS: usize = 100;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Selection {
+    KeepHighest(u32),
+    KeepLowest(u32),
+    DropHighest(u32),
+    DropLowest(u32),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Term {
+    Dice {
+        count: u32,
+        sides: u32,
+        selection: Option<Selection>,
+        explode: bool,
+    },
+    Constant(u32),
+}
+
+/// A parsed roll in standard dice notation, such as `2d20kh1+5`, `4d6dl1` or `1d8!`.
+///
+/// Terms are dice (`NdS`, with an optional `khN`, `klN`, `dhN` or `dlN`
+/// selection and `!` to explode on the highest face) or whole numbers,
+/// joined by `+` and `-`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DiceExpression {
+    /// Each term with its sign, `1` or `-1`.
+    terms: Vec<(i64, Term)>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseError(String);
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+fn error<T>(message: impl Into<String>) -> Result<T, ParseError> {
+    Err(ParseError(message.into()))
+}
+
+struct Parser<'a> {
+    input: &'a [u8],
+    pos: usize,
+}
+
+impl Parser<'_> {
+    fn peek(&self) -> Option<u8> {
+        self.input.get(self.pos).copied()
+    }
+
+    fn eat(&mut self, expected: u8) -> bool {
+        if self.peek() == Some(expected) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn number(&mut self) -> Result<Option<u32>, ParseError> {
+        let start = self.pos;
+        while self.peek().is_some_and(|c| c.is_ascii_digit()) {
+            self.pos += 1;
+        }
+        if start == self.pos {
+            return Ok(None);
+        }
+        let digits = std::str::from_utf8(&self.input[start..self.pos]).unwrap_or_default();
+        digits
+            .parse()
+            .map(Some)
+            .or_else(|_| error(format!("number '{}' is too large", digits)))
+    }
+
+    fn term(&mut self) -> Result<Term, ParseError> {
+        let count = self.number()?;
+        if !self.eat(b'd') {
+            return match count {
+                Some(value) => Ok(Term::Constant(value)),
+                None => error(self.unexpected()),
+            };
+        }
+
+        let count = count.unwrap_or(1);
+        let Some(sides) = self.number()? else {
+            return error("expected the number of sides after 'd'");
+        };
+        if !(1..=MAX_DICE).contains(&count) {
+            return error(format!("a term may roll between 1 and {} dice", MAX_DICE));
+        }
+        if !(1..=MAX_SIDES).contains(&sides) {
+            return error(format!("dice may have between 1 and {} sides", MAX_SIDES));
+        }
+
+        let mut selection = None;
+        let mut explode = false;
+        loop {
+            let modifier = match self.peek() {
+                Some(b'k') => {
+                    self.pos += 1;
+                    if self.eat(b'l') {
+                        Selection::KeepLowest
+                    } else {
+                        self.eat(b'h');
+                        Selection::KeepHighest
+                    }
+                }
+                Some(b'd') => {
+                    self.pos += 1;
+                    if self.eat(b'h') {
+                        Selection::DropHighest
+                    } else if self.eat(b'l') {
+                        Selection::DropLowest
+                    } else {
+                        return error("expected 'h' or 'l' after 'd' in a modifier");
+                    }
+                }
+                Some(b'!') => {
+                    self.pos += 1;
+                    if explode {
+                        return error("a term may only explode once");
+                    }
+                    if sides < 2 {
+                        return error("only dice with at least 2 sides can explode");
+                    }
+                    explode = true;
+                    continue;
+                }
+                _ => break,
+            };
+
+            if selection.is_some() {
+                return error("a term may only keep or drop once");
+            }
+            let amount = self.number()?.unwrap_or(1);
+            if amount > count {
+                return error(format!("cannot keep or drop {} of {} dice", amount, count));
+            }
+            selection = Some(modifier(amount));
+        }
+
+        Ok(Term::Dice {
+            count,
+            sides,
+            selection,
+            explode,
+        })
+    }
+
+    fn unexpected(&self) -> String {
+        match self.peek() {
+            Some(c) => format!("unexpected '{}' at position {}", c as char, self.pos + 1),
+            None => "unexpected end of roll".to_string(),
+        }
+    }
+}
+
+impl FromStr for DiceExpression {
+    type Err = ParseError;
+
+    fn from_str(notation: &str) -> Result<Self, Self::Err> {
+        if notation.len() > MAX_NOTATION_LEN {
+            return error(format!("rolls are limited to {} characters", MAX_NOTATION_LEN));
+        }
+        let notation: String = notation
+            .chars()
+            .filter(|c| !c.is_whitespace())
+            .collect::<String>()
+            .to_ascii_lowercase();
+        if notation.is_empty() {
+            return error("the roll is empty");
+        }
+
+        let mut parser = Parser {
+            input: notation.as_bytes(),
+            pos: 0,
+        };
+        let mut sign = if parser.eat(b'-') { -1 } else { 1 };
+        let mut terms = Vec::new();
+        loop {
+            if terms.len() == MAX_TERMS {
+                return error(format!("rolls are limited to {} terms", MAX_TERMS));
+            }
+            terms.push((sign, parser.term()?));
+
+            sign = if parser.eat(b'+') {
+                1
+            } else if parser.eat(b'-') {
+                -1
+            } else if parser.peek().is_none() {
+                break;
+            } else {
+                return error(parser.unexpected());
+            };
+        }
+
+        if !terms.iter().any(|(_, term)| matches!(term, Term::Dice { .. })) {
+            return error("the roll has no dice");
+        }
+        Ok(Self { terms })
+    }
+}
+
+impl fmt::Display for Term {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Term::Constant(value) => write!(f, "{}", value),
+            Term::Dice {
+                count,
+                sides,
+                selection,
+                explode,
+            } => {
+                write!(f, "{}d{}", count, sides)?;
+                match selection {
+                    Some(Selection::KeepHighest(amount)) => write!(f, "kh{}", amount)?,
+                    Some(Selection::KeepLowest(amount)) => write!(f, "kl{}", amount)?,
+                    Some(Selection::DropHighest(amount)) => write!(f, "dh{}", amount)?,
+                    Some(Selection::DropLowest(amount)) => write!(f, "dl{}", amount)?,
+                    None => {}
+                }
+                if *explode {
+                    f.write_str("!")?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl fmt::Display for DiceExpression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (index, (sign, term)) in self.terms.iter().enumerate() {
+            if *sign < 0 {
+                f.write_str("-")?;
+            } else if index > 0 {
+                f.write_str("+")?;
+            }
+            write!(f, "{}", term)?;
+        }
+        Ok(())
+    }
+}
+
+/// One die as it landed; dropped dice do not count towards the total.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct Die {
+    pub value: u32,
+    #[serde(default)]
+    pub dropped: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TermResult {
+    /// The term as written, with its sign, e.g. `+2d20kh1` or `-1`.
+    pub term: String,
+    /// Empty for whole numbers.
+    pub dice: Vec<Die>,
+    pub total: i64,
+}
+
+impl DiceExpression {
+    /// Rolls every term, returning the per-term breakdown and the grand total.
+    pub fn roll(&self, rng: &mut impl Rng) -> (Vec<TermResult>, i64) {
+        let results: Vec<TermResult> = self
+            .terms
+            .iter()
+            .map(|(sign, term)| {
+                let (dice, subtotal) = roll_term(term, rng);
+                TermResult {
+                    term: format!("{}{}", if *sign < 0 { "-" } else { "+" }, term),
+                    dice,
+                    total: sign * subtotal,
+                }
+            })
+            .collect();
+        let total = results.iter().map(|result| result.total).sum();
+        (results, total)
+    }
+}
+
+fn roll_term(term: &Term, rng: &mut impl Rng) -> (Vec<Die>, i64) {
+    let (count, sides, selection, explode) = match *term {
+        Term::Constant(value) => return (Vec::new(), i64::from(value)),
+        Term::Dice {
+            count,
+            sides,
+            selection,
+            explode,
+        } => (count, sides, selection, explode),
+    };
+
+    let mut dice: Vec<Die> = (0..count)
+        .map(|_| Die {
+            value: rng.gen_range(1..=sides),
+            dropped: false,
+        })
+        .collect();
+
+    // Every die showing its highest face adds another die, which may explode in turn
+    if explode {
+        let mut explosions = 0;
+        let mut index = 0;
+        while index < dice.len() && explosions < MAX_EXPLOSIONS {
+            if dice[index].value == sides {
+                dice.push(Die {
+                    value: rng.gen_range(1..=sides),
+                    dropped: false,
+                });
+                explosions += 1;
+            }
+            index += 1;
+        }
+    }
+
+    if let Some(selection) = selection {
+        let mut order: Vec<usize> = (0..dice.len()).collect();
+        order.sort_by_key(|&index| dice[index].value);
+        let total = dice.len();
+        let dropped = match selection {
+            Selection::KeepHighest(amount) => &order[..total - (amount as usize).min(total)],
+            Selection::KeepLowest(amount) => &order[(amount as usize).min(total)..],
+            Selection::DropHighest(amount) => &order[total - (amount as usize).min(total)..],
+            Selection::DropLowest(amount) => &order[..(amount as usize).min(total)],
+        };
+        for &index in dropped {
+            dice[index].dropped = true;
+        }
+    }
+
+    let subtotal = dice.iter().filter(|die| !die.dropped).map(|die| i64::from(die.value)).sum();
+    (dice, subtotal)
+}
+
+/// A roll made by the server on a player's behalf, as broadcast and kept in the roll log.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DiceRoll {
+    pub id: String,
+    pub player_id: String,
+    pub player_name: String,
+    /// The roll in normalized notation.
+    pub notation: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub label: Option<String>,
+    /// Secret rolls are only shown to the roller and the game master.
+    pub secret: bool,
+    pub terms: Vec<TermResult>,
+    pub total: i64,
+    /// Seconds since the Unix epoch.
+    pub rolled_at: u64,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    fn parse(notation: &str) -> Result<DiceExpression, ParseError> {
+        notation.parse()
+    }
+
+    fn dice(count: u32, sides: u32, selection: Option<Selection>, explode: bool) -> Term {
+        Term::Dice {
+            count,
+            sides,
+            selection,
+            explode,
+        }
+    }
+
+    #[test]
+    fn parses_the_documented_examples() {
+        assert_eq!(
+            parse("2d20kh1+5").unwrap().terms,
+            vec![(1, dice(2, 20, Some(Selection::KeepHighest(1)), false)), (1, Term::Constant(5))]
+        );
+        assert_eq!(parse("4d6dl1").unwrap().terms, vec![(1, dice(4, 6, Some(Selection::DropLowest(1)), false))]);
+        assert_eq!(parse("1d8!").unwrap().terms, vec![(1, dice(1, 8, None, true))]);
+    }
+
+    #[test]
+    fn defaults_to_one_die() {
+        assert_eq!(parse("d20").unwrap().terms, vec![(1, dice(1, 20, None, false))]);
+    }
+
+    #[test]
+    fn accepts_a_leading_minus() {
+        let expression = parse("-1d4 + 3").unwrap();
+        assert_eq!(expression.terms, vec![(-1, dice(1, 4, None, false)), (1, Term::Constant(3))]);
+        assert_eq!(expression.to_string(), "-1d4+3");
+    }
+
+    #[test]
+    fn normalizes_notation() {
+        assert_eq!(parse(" 2D20K1 + 5 ").unwrap().to_string(), "2d20kh1+5");
+    }
+
+    #[test]
+    fn rejects_invalid_rolls() {
+        for notation in ["", "2d6d", "0d6", "3d6kh4", "1d1!", "1d6!!", "4d6kh1dl1", "5", "2d", "1d6+", "1d6*2", "1d1001", "101d6"] {
+            assert!(parse(notation).is_err(), "{} should be rejected", notation);
+        }
+    }
+
+    #[test]
+    fn limits_the_number_of_terms() {
+        let notation = format!("1d4{}", "+1".repeat(MAX_TERMS - 1));
+        assert!(parse(&notation).is_ok());
+        assert_eq!(
+            parse(&format!("{}+1", notation)).unwrap_err().to_string(),
+            format!("rolls are limited to {} terms", MAX_TERMS)
+        );
+    }
+
+    #[test]
+    fn marks_kept_and_dropped_dice() {
+        let mut rng = StdRng::seed_from_u64(7);
+        for _ in 0..100 {
+            let (terms, total) = parse("4d6dl1").unwrap().roll(&mut rng);
+            let dice = &terms[0].dice;
+            assert_eq!(dice.len(), 4);
+            assert_eq!(dice.iter().filter(|die| die.dropped).count(), 1);
+            let lowest = dice.iter().map(|die| die.value).min().unwrap();
+            let dropped = dice.iter().find(|die| die.dropped).unwrap();
+            assert_eq!(dropped.value, lowest);
+            let kept: i64 = dice.iter().filter(|die| !die.dropped).map(|die| i64::from(die.value)).sum();
+            assert_eq!(total, kept);
+
+            let (terms, total) = parse("2d20kh1+5").unwrap().roll(&mut rng);
+            let highest = terms[0].dice.iter().map(|die| die.value).max().unwrap();
+            assert_eq!(terms[0].dice.iter().filter(|die| !die.dropped).count(), 1);
+            assert_eq!(total, i64::from(highest) + 5);
+            assert_eq!(terms[1].term, "+5");
+        }
+    }
+
+    #[test]
+    fn explodes_on_the_highest_face() {
+        let mut rng = StdRng::seed_from_u64(11);
+        let mut exploded = false;
+        for _ in 0..200 {
+            let (terms, total) = parse("1d2!").unwrap().roll(&mut rng);
+            let dice = &terms[0].dice;
+            // Every die but the last showed the highest face
+            assert!(dice[..dice.len() - 1].iter().all(|die| die.value == 2));
+            assert_eq!(total, dice.iter().map(|die| i64::from(die.value)).sum::<i64>());
+            exploded |= dice.len() > 1;
+        }
+        assert!(exploded);
+    }
+
+    #[test]
+    fn subtracts_negative_terms() {
+        let mut rng = StdRng::seed_from_u64(3);
+        let (terms, total) = parse("-1d4-2").unwrap().roll(&mut rng);
+        assert_eq!(terms[0].term, "-1d4");
+        assert_eq!(total, -i64::from(terms[0].dice[0].value) - 2);
+    }
+}
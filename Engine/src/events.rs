@@ -0,0 +1,188 @@
+use log::error;
+use serde::{Deserialize, Serialize};
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::chat::ChatMessage;
+use crate::dice::DiceRoll;
+use crate::initiative::InitiativeSlot;
+use crate::map::MapDefinition;
+use crate::pathfinding::MovementUsed;
+use crate::token::{ActiveCondition, Condition, HitPoints, NpcChanges, NpcToken, TokenSize, TokenStatus};
+use crate::{GameState, Position};
+
+/// One accepted change to a room's game state. Each `GameState` method that
+/// changes the state writes one, and `GameState::apply` replays it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum GameEvent {
+    /// The room was created on `map`; replay starts over from a fresh state.
+    RoomCreated {
+        map: Box<MapDefinition>,
+    },
+    /// The room was restored from a snapshot when the server started.
+    RoomRestored {
+        state: Box<GameState>,
+    },
+    /// The room emptied or sat idle and was torn down.
+    RoomClosed,
+    PlayerAdded {
+        player_id: String,
+        name: String,
+        color: String,
+        size: TokenSize,
+        position: Position,
+    },
+    GameMasterGranted {
+        player_id: String,
+    },
+    /// Logged so replayed snapshots let players reconnect, which makes the log
+    /// as secret as the snapshot file.
+    ReconnectTokenIssued {
+        player_id: String,
+        token: String,
+    },
+    PlayerOnline {
+        player_id: String,
+        online: bool,
+    },
+    /// The server restarted from a snapshot, which marks every player offline.
+    AllPlayersOffline,
+    PlayerRemoved {
+        player_id: String,
+    },
+    TokenMoved {
+        token_id: String,
+        position: Position,
+    },
+    /// `used` is the token's movement spent this turn after the move.
+    MovementSpent {
+        token_id: String,
+        used: MovementUsed,
+    },
+    MovementLocked {
+        locked: bool,
+    },
+    MovementReset,
+    BoardReset,
+    DoorSet {
+        position: Position,
+        open: bool,
+    },
+    EncounterStarted {
+        restrict_movement: bool,
+    },
+    InitiativeSubmitted {
+        token_id: String,
+        initiative: i32,
+    },
+    TurnAdvanced,
+    /// A removed token was put back into the encounter by an undo.
+    InitiativeRestored {
+        slot: InitiativeSlot,
+    },
+    EncounterEnded,
+    NpcAdded {
+        token_id: String,
+        token: Box<NpcToken>,
+    },
+    NpcUpdated {
+        token_id: String,
+        changes: NpcChanges,
+    },
+    NpcRemoved {
+        token_id: String,
+    },
+    StatusUpdated {
+        token_id: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        hp: Option<HitPoints>,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        add_conditions: Vec<ActiveCondition>,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        remove_conditions: Vec<Condition>,
+    },
+    TokenResized {
+        token_id: String,
+        size: TokenSize,
+    },
+    /// A token's whole status was put back by an undo or redo.
+    StatusSet {
+        token_id: String,
+        status: TokenStatus,
+    },
+    RollLogged(Box<DiceRoll>),
+    ChatLogged(Box<ChatMessage>),
+    /// The state version clients saw went up to `version`, so a replayed
+    /// snapshot never sends them back to an older one.
+    VersionBumped {
+        version: u64,
+    },
+}
+
+/// One line of the event log.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LogEntry {
+    /// Milliseconds since the Unix epoch.
+    pub timestamp_ms: u64,
+    pub room: String,
+    pub event: GameEvent,
+}
+
+/// An append-only JSON Lines file shared by every room.
+#[derive(Debug)]
+pub struct EventLog {
+    file: Mutex<File>,
+}
+
+impl EventLog {
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self { file: Mutex::new(file) })
+    }
+
+    fn append(&self, entry: &LogEntry) {
+        let mut line = match serde_json::to_string(entry) {
+            Ok(line) => line,
+            Err(e) => {
+                error!("Failed to serialize event for room {}: {}", entry.room, e);
+                return;
+            }
+        };
+        line.push('\n');
+
+        // One write per line so a crash can at worst truncate the last event
+        let mut file = self.file.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        if let Err(e) = file.write_all(line.as_bytes()) {
+            error!("Failed to append event for room {}: {}", entry.room, e);
+        }
+    }
+}
+
+/// A room's handle on the shared event log.
+#[derive(Debug, Clone)]
+pub struct RoomLog {
+    room_id: String,
+    log: Arc<EventLog>,
+}
+
+impl RoomLog {
+    pub fn new(room_id: String, log: Arc<EventLog>) -> Self {
+        Self { room_id, log }
+    }
+
+    pub fn append(&self, event: GameEvent) {
+        let timestamp_ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_millis() as u64)
+            .unwrap_or_default();
+        self.log.append(&LogEntry {
+            timestamp_ms,
+            room: self.room_id.clone(),
+            event,
+        });
+    }
+}
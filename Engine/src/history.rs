@@ -0,0 +1,141 @@
+use std::collections::VecDeque;
+
+use crate::initiative::{InitiativeSlot, Turn};
+use crate::pathfinding::MovementUsed;
+use crate::token::{NpcToken, TokenStatus};
+use crate::Position;
+
+/// How many board changes the game master can undo.
+pub const UNDO_HISTORY_LEN: usize = 50;
+
+/// A change to the board that the game master can undo, carrying enough to
+/// apply it in either direction.
+#[derive(Debug, Clone)]
+pub enum BoardChange {
+    TokenMoved {
+        token_id: String,
+        from: Position,
+        to: Position,
+        /// Movement the token had spent this turn before and after the move.
+        used_before: MovementUsed,
+        used_after: MovementUsed,
+        /// The encounter turn the move was made in; the movement spent is only
+        /// put back while it is still that turn.
+        turn: Option<Turn>,
+    },
+    /// `open` is the door's state after the change.
+    DoorSet {
+        position: Position,
+        open: bool,
+    },
+    /// `initiative` is set when the token is put back after a removal.
+    NpcCreated {
+        token_id: String,
+        token: Box<NpcToken>,
+        initiative: Option<InitiativeSlot>,
+    },
+    NpcUpdated {
+        token_id: String,
+        before: Box<NpcToken>,
+        after: Box<NpcToken>,
+    },
+    /// `initiative` is where the token stood in the encounter, if it had rolled.
+    NpcRemoved {
+        token_id: String,
+        token: Box<NpcToken>,
+        initiative: Option<InitiativeSlot>,
+    },
+    StatusUpdated {
+        token_id: String,
+        before: TokenStatus,
+        after: TokenStatus,
+    },
+}
+
+impl BoardChange {
+    /// The change that takes the board back to where it was before this one.
+    pub fn inverse(self) -> BoardChange {
+        match self {
+            BoardChange::TokenMoved { token_id, from, to, used_before, used_after, turn } => BoardChange::TokenMoved {
+                token_id,
+                from: to,
+                to: from,
+                used_before: used_after,
+                used_after: used_before,
+                turn,
+            },
+            BoardChange::DoorSet { position, open } => BoardChange::DoorSet { position, open: !open },
+            BoardChange::NpcCreated { token_id, token, initiative } => BoardChange::NpcRemoved { token_id, token, initiative },
+            BoardChange::NpcRemoved { token_id, token, initiative } => BoardChange::NpcCreated { token_id, token, initiative },
+            BoardChange::NpcUpdated { token_id, before, after } => BoardChange::NpcUpdated {
+                token_id,
+                before: after,
+                after: before,
+            },
+            BoardChange::StatusUpdated { token_id, before, after } => BoardChange::StatusUpdated {
+                token_id,
+                before: after,
+                after: before,
+            },
+        }
+    }
+
+    /// The token the change is about, if any.
+    fn token_id(&self) -> Option<&str> {
+        match self {
+            BoardChange::TokenMoved { token_id, .. }
+            | BoardChange::NpcCreated { token_id, .. }
+            | BoardChange::NpcUpdated { token_id, .. }
+            | BoardChange::NpcRemoved { token_id, .. }
+            | BoardChange::StatusUpdated { token_id, .. } => Some(token_id),
+            BoardChange::DoorSet { .. } => None,
+        }
+    }
+}
+
+/// The last board changes, most recent last, plus the ones undone since.
+#[derive(Debug, Clone, Default)]
+pub struct BoardHistory {
+    undo: VecDeque<BoardChange>,
+    redo: Vec<BoardChange>,
+}
+
+impl BoardHistory {
+    /// Remembers a new change. Anything undone before it can no longer be redone.
+    pub fn record(&mut self, change: BoardChange) {
+        self.redo.clear();
+        self.push_undo(change);
+    }
+
+    /// Makes `change` the next one to undo, keeping what can be redone.
+    pub fn push_undo(&mut self, change: BoardChange) {
+        if self.undo.len() == UNDO_HISTORY_LEN {
+            self.undo.pop_front();
+        }
+        self.undo.push_back(change);
+    }
+
+    pub fn pop_undo(&mut self) -> Option<BoardChange> {
+        self.undo.pop_back()
+    }
+
+    pub fn pop_redo(&mut self) -> Option<BoardChange> {
+        self.redo.pop()
+    }
+
+    /// Makes `change` the next one to redo.
+    pub fn push_redo(&mut self, change: BoardChange) {
+        self.redo.push(change);
+    }
+
+    /// Drops every change about a token that left the game for good.
+    pub fn forget(&mut self, token_id: &str) {
+        self.undo.retain(|change| change.token_id() != Some(token_id));
+        self.redo.retain(|change| change.token_id() != Some(token_id));
+    }
+
+    pub fn clear(&mut self) {
+        self.undo.clear();
+        self.redo.clear();
+    }
+}
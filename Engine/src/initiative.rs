@@ -0,0 +1,346 @@
+use std::cmp::Reverse;
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct InitiativeEntry {
+    pub player_id: String,
+    pub initiative: i32,
+}
+
+/// Where a token stood in the order, kept so undoing its removal can put it back.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct InitiativeSlot {
+    pub index: usize,
+    pub entry: InitiativeEntry,
+    /// The round it was removed in, if it was the token's turn.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub active_round: Option<u32>,
+}
+
+/// Whose turn it is and in which round.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Turn {
+    pub round: u32,
+    pub player_id: String,
+}
+
+/// A combat encounter: the initiative order and whose turn it is.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Encounter {
+    /// Highest initiative first; ties keep submission order. A roll changed
+    /// mid-round keeps its place until the next round starts.
+    pub order: Vec<InitiativeEntry>,
+    /// The token whose turn it is; `None` until the first `next_turn`.
+    pub active: Option<String>,
+    /// Starts at 1 with the first turn.
+    pub round: u32,
+    /// Only the active token may move while set.
+    pub restrict_movement: bool,
+}
+
+impl Encounter {
+    pub fn new(restrict_movement: bool) -> Self {
+        Self {
+            order: Vec::new(),
+            active: None,
+            round: 0,
+            restrict_movement,
+        }
+    }
+
+    /// Adds `player_id` to the order, or replaces their earlier roll. Once
+    /// turns are running, a replaced roll keeps its place for the rest of the
+    /// round so nobody gains or loses a turn.
+    pub fn submit(&mut self, player_id: &str, initiative: i32) {
+        let existing = self.order.iter_mut().find(|entry| entry.player_id == player_id);
+        if let (Some(entry), Some(_)) = (existing, &self.active) {
+            entry.initiative = initiative;
+            return;
+        }
+
+        self.order.retain(|entry| entry.player_id != player_id);
+        let index = self
+            .order
+            .iter()
+            .position(|entry| entry.initiative < initiative)
+            .unwrap_or(self.order.len());
+        self.order.insert(
+            index,
+            InitiativeEntry {
+                player_id: player_id.to_string(),
+                initiative,
+            },
+        );
+    }
+
+    /// Passes the turn to the next token in the order, starting a new round
+    /// after the last one. Returns the new active token, or `None` if nobody
+    /// has rolled initiative.
+    pub fn advance(&mut self) -> Option<&str> {
+        if self.order.is_empty() {
+            return None;
+        }
+
+        let next = match self.active_index() {
+            Some(index) if index + 1 < self.order.len() => index + 1,
+            _ => {
+                self.start_round();
+                0
+            }
+        };
+        self.active = Some(self.order[next].player_id.clone());
+        self.active.as_deref()
+    }
+
+    /// Drops `player_id` from the order; if it was their turn, the turn passes on.
+    pub fn remove(&mut self, player_id: &str) {
+        let Some(index) = self.order.iter().position(|entry| entry.player_id == player_id) else {
+            return;
+        };
+
+        self.order.remove(index);
+        if self.active.as_deref() != Some(player_id) {
+            return;
+        }
+
+        self.active = if self.order.is_empty() {
+            None
+        } else if index < self.order.len() {
+            Some(self.order[index].player_id.clone())
+        } else {
+            self.start_round();
+            Some(self.order[0].player_id.clone())
+        };
+    }
+
+    /// Where `player_id` stands in the order, if they rolled.
+    pub fn slot_of(&self, player_id: &str) -> Option<InitiativeSlot> {
+        let index = self.order.iter().position(|entry| entry.player_id == player_id)?;
+        Some(InitiativeSlot {
+            index,
+            entry: self.order[index].clone(),
+            active_round: self.is_turn_of(player_id).then_some(self.round),
+        })
+    }
+
+    /// Puts a removed token back where it stood. If it lost its turn to the
+    /// removal and the turn has not moved on since, it gets the turn back;
+    /// returns whether it did.
+    pub fn restore(&mut self, slot: InitiativeSlot) -> bool {
+        if self.order.iter().any(|entry| entry.player_id == slot.entry.player_id) {
+            return false;
+        }
+
+        let index = slot.index.min(self.order.len());
+        // Where `remove` would have passed the turn
+        let takes_turn_back = slot.active_round.is_some_and(|round| match self.order.get(index) {
+            Some(next) => self.is_turn_of(&next.player_id) && self.round == round,
+            None => match self.order.first() {
+                Some(first) => self.is_turn_of(&first.player_id) && self.round == round + 1,
+                None => self.active.is_none() && self.round == round,
+            },
+        });
+
+        let player_id = slot.entry.player_id.clone();
+        self.order.insert(index, slot.entry);
+        if let (true, Some(round)) = (takes_turn_back, slot.active_round) {
+            self.active = Some(player_id);
+            self.round = round;
+        }
+        takes_turn_back
+    }
+
+    pub fn turn(&self) -> Option<Turn> {
+        Some(Turn {
+            round: self.round,
+            player_id: self.active.clone()?,
+        })
+    }
+
+    pub fn is_turn_of(&self, player_id: &str) -> bool {
+        self.active.as_deref() == Some(player_id)
+    }
+
+    /// Applies rolls changed during the last round; the sort is stable, so
+    /// ties keep their order.
+    fn start_round(&mut self) {
+        self.round += 1;
+        self.order.sort_by_key(|entry| Reverse(entry.initiative));
+    }
+
+    fn active_index(&self) -> Option<usize> {
+        let active = self.active.as_deref()?;
+        self.order.iter().position(|entry| entry.player_id == active)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encounter(rolls: &[(&str, i32)]) -> Encounter {
+        let mut encounter = Encounter::new(false);
+        for (player_id, initiative) in rolls {
+            encounter.submit(player_id, *initiative);
+        }
+        encounter
+    }
+
+    fn order(encounter: &Encounter) -> Vec<&str> {
+        encounter.order.iter().map(|entry| entry.player_id.as_str()).collect()
+    }
+
+    #[test]
+    fn submit_keeps_highest_first_and_ties_in_arrival_order() {
+        let encounter = encounter(&[("a", 10), ("b", 15), ("c", 10), ("d", 20)]);
+        assert_eq!(order(&encounter), ["d", "b", "a", "c"]);
+    }
+
+    #[test]
+    fn resubmitting_before_the_first_turn_moves_the_entry() {
+        let mut encounter = encounter(&[("a", 20), ("b", 15), ("c", 10)]);
+        encounter.submit("a", 12);
+        assert_eq!(order(&encounter), ["b", "a", "c"]);
+        assert_eq!(encounter.order[1].initiative, 12);
+    }
+
+    #[test]
+    fn advance_walks_the_order_and_counts_rounds() {
+        let mut encounter = encounter(&[("a", 20), ("b", 15)]);
+        assert_eq!(encounter.round, 0);
+        assert_eq!(encounter.advance(), Some("a"));
+        assert_eq!(encounter.round, 1);
+        assert_eq!(encounter.advance(), Some("b"));
+        assert_eq!(encounter.advance(), Some("a"));
+        assert_eq!(encounter.round, 2);
+    }
+
+    #[test]
+    fn advance_without_rolls_returns_none() {
+        let mut encounter = Encounter::new(false);
+        assert_eq!(encounter.advance(), None);
+        assert_eq!(encounter.round, 0);
+    }
+
+    #[test]
+    fn resubmitting_mid_round_does_not_skip_turns() {
+        let mut encounter = encounter(&[("a", 20), ("b", 15), ("c", 10)]);
+        encounter.advance();
+        encounter.submit("a", 12);
+        assert_eq!(order(&encounter), ["a", "b", "c"]);
+        assert_eq!(encounter.advance(), Some("b"));
+        assert_eq!(encounter.advance(), Some("c"));
+        assert_eq!(encounter.advance(), Some("b"));
+        assert_eq!(order(&encounter), ["b", "a", "c"]);
+        assert_eq!(encounter.round, 2);
+    }
+
+    #[test]
+    fn resubmitting_a_later_token_mid_round_gives_no_extra_turn() {
+        let mut encounter = encounter(&[("a", 20), ("b", 15), ("c", 10)]);
+        encounter.advance();
+        encounter.advance();
+        encounter.submit("c", 30);
+        assert_eq!(encounter.advance(), Some("c"));
+        assert_eq!(encounter.advance(), Some("c"));
+        assert_eq!(encounter.round, 2);
+        assert_eq!(encounter.advance(), Some("a"));
+    }
+
+    #[test]
+    fn joining_mid_round_takes_a_place_by_roll() {
+        let mut encounter = encounter(&[("a", 20), ("c", 10)]);
+        encounter.advance();
+        encounter.submit("b", 15);
+        assert_eq!(order(&encounter), ["a", "b", "c"]);
+        assert_eq!(encounter.advance(), Some("b"));
+    }
+
+    #[test]
+    fn removing_the_active_token_passes_the_turn() {
+        let mut encounter = encounter(&[("a", 20), ("b", 15), ("c", 10)]);
+        encounter.advance();
+        encounter.remove("a");
+        assert_eq!(encounter.active.as_deref(), Some("b"));
+        assert_eq!(encounter.round, 1);
+    }
+
+    #[test]
+    fn removing_the_last_active_token_starts_the_next_round() {
+        let mut encounter = encounter(&[("a", 20), ("b", 15)]);
+        encounter.advance();
+        encounter.advance();
+        encounter.remove("b");
+        assert_eq!(encounter.active.as_deref(), Some("a"));
+        assert_eq!(encounter.round, 2);
+    }
+
+    #[test]
+    fn removing_another_token_keeps_the_turn() {
+        let mut encounter = encounter(&[("a", 20), ("b", 15), ("c", 10)]);
+        encounter.advance();
+        encounter.advance();
+        encounter.remove("a");
+        assert!(encounter.is_turn_of("b"));
+        assert_eq!(encounter.advance(), Some("c"));
+    }
+
+    #[test]
+    fn restoring_a_removed_token_takes_the_turn_back() {
+        let mut encounter = encounter(&[("a", 20), ("b", 15), ("c", 10)]);
+        encounter.advance();
+        let slot = encounter.slot_of("a").unwrap();
+        encounter.remove("a");
+        assert!(encounter.restore(slot));
+        assert_eq!(order(&encounter), ["a", "b", "c"]);
+        assert!(encounter.is_turn_of("a"));
+        assert_eq!(encounter.advance(), Some("b"));
+    }
+
+    #[test]
+    fn restoring_the_last_token_takes_back_the_round() {
+        let mut encounter = encounter(&[("a", 20), ("b", 15)]);
+        encounter.advance();
+        encounter.advance();
+        let slot = encounter.slot_of("b").unwrap();
+        encounter.remove("b");
+        assert_eq!(encounter.round, 2);
+        assert!(encounter.restore(slot));
+        assert!(encounter.is_turn_of("b"));
+        assert_eq!(encounter.round, 1);
+    }
+
+    #[test]
+    fn restoring_after_the_turn_moved_on_keeps_the_turn() {
+        let mut encounter = encounter(&[("a", 20), ("b", 15), ("c", 10)]);
+        encounter.advance();
+        let slot = encounter.slot_of("a").unwrap();
+        encounter.remove("a");
+        encounter.advance();
+        assert!(!encounter.restore(slot));
+        assert_eq!(order(&encounter), ["a", "b", "c"]);
+        assert!(encounter.is_turn_of("c"));
+    }
+
+    #[test]
+    fn restoring_an_inactive_token_keeps_the_turn() {
+        let mut encounter = encounter(&[("a", 20), ("b", 15), ("c", 10)]);
+        encounter.advance();
+        let slot = encounter.slot_of("b").unwrap();
+        assert_eq!(slot.active_round, None);
+        encounter.remove("b");
+        assert!(!encounter.restore(slot));
+        assert_eq!(order(&encounter), ["a", "b", "c"]);
+        assert!(encounter.is_turn_of("a"));
+    }
+
+    #[test]
+    fn removing_the_only_token_clears_the_turn() {
+        let mut encounter = encounter(&[("a", 20)]);
+        encounter.advance();
+        encounter.remove("a");
+        assert_eq!(encounter.active, None);
+        assert!(encounter.order.is_empty());
+    }
+}
@@ -0,0 +1,319 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::protocol::ClientMessage;
+
+/// How long a client must stay within its limits for its strikes to be forgiven.
+const STRIKE_WINDOW: Duration = Duration::from_secs(60);
+/// Least time between two rate limit warnings to the same client; going over
+/// the limit again within it is not another strike.
+const WARNING_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Caps how many WebSocket connections are open at once across all rooms.
+#[derive(Debug)]
+pub struct ConnectionLimit {
+    open: AtomicUsize,
+    /// `None` is unlimited.
+    max: Option<usize>,
+}
+
+impl ConnectionLimit {
+    pub fn new(max: Option<usize>) -> Arc<Self> {
+        Arc::new(Self {
+            open: AtomicUsize::new(0),
+            max,
+        })
+    }
+
+    /// Reserves a slot for a new connection, or returns `None` if the server is full.
+    pub fn try_acquire(self: &Arc<Self>) -> Option<ConnectionSlot> {
+        self.open
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |open| match self.max {
+                Some(max) if open >= max => None,
+                _ => Some(open + 1),
+            })
+            .ok()?;
+        Some(ConnectionSlot { limit: self.clone() })
+    }
+}
+
+/// A reserved connection slot, freed when dropped. Holding it across the
+/// upgrade frees it even if the handshake never completes.
+#[derive(Debug)]
+pub struct ConnectionSlot {
+    limit: Arc<ConnectionLimit>,
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        self.limit.open.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+/// Refills `rate` tokens a second up to `burst`; each message takes one.
+#[derive(Debug, Clone)]
+pub struct TokenBucket {
+    rate: f64,
+    burst: f64,
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl TokenBucket {
+    /// A bucket that starts full.
+    pub fn new(rate: f64, burst: f64) -> Self {
+        Self {
+            rate,
+            burst,
+            tokens: burst,
+            refilled: Instant::now(),
+        }
+    }
+
+    /// Takes a token if one is left.
+    pub fn try_take(&mut self, now: Instant) -> bool {
+        let elapsed = now.saturating_duration_since(self.refilled).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.burst);
+        self.refilled = now;
+        if self.tokens < 1.0 {
+            return false;
+        }
+        self.tokens -= 1.0;
+        true
+    }
+}
+
+/// Message types with a budget of their own on top of the connection's, as
+/// each one is handled, logged or rebroadcast to the whole room.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MessageKind {
+    Move,
+    Roll,
+    Chat,
+    /// Binary frames, relayed as-is to every other client.
+    Binary,
+}
+
+impl MessageKind {
+    const ALL: [MessageKind; 4] = [MessageKind::Move, MessageKind::Roll, MessageKind::Chat, MessageKind::Binary];
+
+    /// The budget of text messages of this type, if they have one.
+    pub fn of(message: &ClientMessage) -> Option<Self> {
+        match message {
+            ClientMessage::PlayerMove { .. } => Some(MessageKind::Move),
+            ClientMessage::Roll { .. } => Some(MessageKind::Roll),
+            ClientMessage::Chat { .. } => Some(MessageKind::Chat),
+            _ => None,
+        }
+    }
+
+    /// Messages per second and burst.
+    fn limit(self) -> (f64, f64) {
+        match self {
+            MessageKind::Move => (10.0, 20.0),
+            MessageKind::Roll => (2.0, 10.0),
+            MessageKind::Chat => (2.0, 10.0),
+            MessageKind::Binary => (5.0, 10.0),
+        }
+    }
+
+    fn index(self) -> usize {
+        self as usize
+    }
+}
+
+/// Per-connection message budget.
+#[derive(Debug, Clone, Copy)]
+pub struct RateLimit {
+    /// Messages per second a client may keep sending.
+    pub messages_per_sec: u32,
+    /// Messages a client may send at once before the rate applies.
+    pub burst: u32,
+    /// Warnings a client may get for going over the limit before the next
+    /// time disconnects it.
+    pub strikes: u32,
+}
+
+/// What to do with a message that went through a [`RateLimiter`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verdict {
+    Allow,
+    /// Over the limit; ignore the message.
+    Drop,
+    /// Over the limit; ignore the message and warn the client.
+    Warn,
+    /// Over the limit too often; disconnect the client.
+    Disconnect,
+}
+
+/// Rate limits the frames of one connection: every message takes from the
+/// connection's bucket, and moves, rolls, chat and binary frames also from
+/// their own. Each message over a limit is dropped. The first one earns a
+/// warning, and each warning interval the client keeps going over the limit
+/// after that is a strike with another warning.
+#[derive(Debug)]
+pub struct RateLimiter {
+    connection: TokenBucket,
+    kinds: [TokenBucket; 4],
+    max_strikes: u32,
+    strikes: u32,
+    warned: Option<Instant>,
+}
+
+impl RateLimiter {
+    pub fn new(limit: RateLimit) -> Self {
+        Self {
+            connection: TokenBucket::new(limit.messages_per_sec.into(), limit.burst.into()),
+            kinds: MessageKind::ALL.map(|kind| {
+                let (rate, burst) = kind.limit();
+                TokenBucket::new(rate, burst)
+            }),
+            max_strikes: limit.strikes,
+            strikes: 0,
+            warned: None,
+        }
+    }
+
+    /// Charges a message to the connection and, if given, its type.
+    pub fn check(&mut self, kind: Option<MessageKind>) -> Verdict {
+        self.check_at(kind, Instant::now())
+    }
+
+    fn check_at(&mut self, kind: Option<MessageKind>, now: Instant) -> Verdict {
+        if self.connection.try_take(now) && kind.is_none_or(|kind| self.kinds[kind.index()].try_take(now)) {
+            return Verdict::Allow;
+        }
+
+        match self.warned.map(|warned| now.saturating_duration_since(warned)) {
+            Some(since) if since < WARNING_INTERVAL => return Verdict::Drop,
+            Some(since) if since < STRIKE_WINDOW => self.strikes += 1,
+            _ => self.strikes = 0,
+        }
+        if self.strikes >= self.max_strikes {
+            return Verdict::Disconnect;
+        }
+        self.warned = Some(now);
+        Verdict::Warn
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limiter(messages_per_sec: u32, burst: u32, strikes: u32) -> RateLimiter {
+        RateLimiter::new(RateLimit {
+            messages_per_sec,
+            burst,
+            strikes,
+        })
+    }
+
+    /// Sends messages at `at` until one is not allowed, returning its verdict.
+    fn flood(limiter: &mut RateLimiter, at: Instant) -> Verdict {
+        loop {
+            match limiter.check_at(None, at) {
+                Verdict::Allow => continue,
+                verdict => return verdict,
+            }
+        }
+    }
+
+    fn secs(secs: f64) -> Duration {
+        Duration::from_secs_f64(secs)
+    }
+
+    #[test]
+    fn bucket_starts_full_and_empties() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(1.0, 3.0);
+        assert!(bucket.try_take(start));
+        assert!(bucket.try_take(start));
+        assert!(bucket.try_take(start));
+        assert!(!bucket.try_take(start));
+    }
+
+    #[test]
+    fn bucket_refills_at_its_rate() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(2.0, 2.0);
+        assert!(bucket.try_take(start));
+        assert!(bucket.try_take(start));
+        assert!(!bucket.try_take(start + secs(0.25)));
+        assert!(bucket.try_take(start + secs(0.5)));
+        assert!(!bucket.try_take(start + secs(0.5)));
+    }
+
+    #[test]
+    fn bucket_never_holds_more_than_its_burst() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(10.0, 2.0);
+        let later = start + secs(60.0);
+        assert!(bucket.try_take(later));
+        assert!(bucket.try_take(later));
+        assert!(!bucket.try_take(later));
+    }
+
+    #[test]
+    fn messages_within_the_burst_are_allowed() {
+        let start = Instant::now();
+        let mut limiter = limiter(1, 5, 3);
+        for _ in 0..5 {
+            assert_eq!(limiter.check_at(None, start), Verdict::Allow);
+        }
+    }
+
+    #[test]
+    fn message_kinds_have_their_own_budget() {
+        let start = Instant::now();
+        let mut limiter = limiter(100, 100, 3);
+        for _ in 0..10 {
+            assert_eq!(limiter.check_at(Some(MessageKind::Chat), start), Verdict::Allow);
+        }
+        assert_eq!(limiter.check_at(Some(MessageKind::Chat), start), Verdict::Warn);
+        assert_eq!(limiter.check_at(Some(MessageKind::Move), start), Verdict::Allow);
+        assert_eq!(limiter.check_at(None, start), Verdict::Allow);
+    }
+
+    #[test]
+    fn a_single_burst_is_warned_once_and_never_disconnected() {
+        let start = Instant::now();
+        let mut limiter = limiter(1, 10, 1);
+        let verdicts: Vec<Verdict> = (0..80).map(|_| limiter.check_at(Some(MessageKind::Chat), start)).collect();
+        assert!(verdicts[..10].iter().all(|verdict| *verdict == Verdict::Allow));
+        assert_eq!(verdicts[10], Verdict::Warn);
+        assert!(verdicts[11..].iter().all(|verdict| *verdict == Verdict::Drop));
+    }
+
+    #[test]
+    fn abuse_after_each_warning_interval_is_a_strike() {
+        let start = Instant::now();
+        let mut limiter = limiter(1, 1, 2);
+        assert_eq!(limiter.check_at(None, start), Verdict::Allow);
+        assert_eq!(limiter.check_at(None, start), Verdict::Warn);
+
+        // Each interval the bucket refills a few tokens, which the flood uses up.
+        let mut at = start;
+        for verdict in [Verdict::Warn, Verdict::Disconnect] {
+            at += WARNING_INTERVAL;
+            assert_eq!(flood(&mut limiter, at), verdict);
+        }
+    }
+
+    #[test]
+    fn strikes_are_forgiven_after_a_quiet_minute() {
+        let start = Instant::now();
+        let mut limiter = limiter(1, 1, 2);
+        limiter.check_at(None, start);
+        assert_eq!(limiter.check_at(None, start), Verdict::Warn);
+
+        let later = start + WARNING_INTERVAL;
+        assert_eq!(flood(&mut limiter, later), Verdict::Warn);
+        assert_eq!(limiter.strikes, 1);
+
+        let much_later = later + STRIKE_WINDOW;
+        assert_eq!(flood(&mut limiter, much_later), Verdict::Warn);
+        assert_eq!(limiter.strikes, 0);
+    }
+}
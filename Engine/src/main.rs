@@ -1,102 +1,433 @@
-use log::{error, info};
-use std::env;
-use std::net::SocketAddr;
-use std::collections::HashMap;
-use warp::{ws::Message, Filter, Rejection, Reply};
-use futures::{SinkExt, StreamExt};
-use tokio::sync::RwLock;
+use log::{debug, error, info, warn};
+use std::process;
+use std::collections::{HashMap, HashSet, VecDeque};
+use warp::{http::StatusCode, ws::Message, Filter, Rejection, Reply};
+use futures::StreamExt;
+use tokio::sync::{oneshot, RwLock};
+use std::sync::atomic::AtomicBool;
 use std::sync::Arc;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
 use uuid::Uuid;
 use serde::{Deserialize, Serialize};
-use serde_json;
 
-type Clients = Arc<RwLock<HashMap<String, futures::stream::SplitSink<warp::ws::WebSocket, warp::ws::Message>>>>;
+mod chat;
+mod config;
+mod dice;
+mod events;
+mod history;
+mod initiative;
+mod limits;
+mod map;
+mod origin;
+mod outbound;
+mod pathfinding;
+mod persistence;
+mod protocol;
+mod replay;
+mod room;
+mod shutdown;
+mod token;
+mod visibility;
+
+use chat::{ChatChannel, ChatMessage};
+use clap::Parser;
+use config::{Cli, Command, ServerConfig};
+use dice::{DiceExpression, DiceRoll};
+use events::{EventLog, GameEvent, RoomLog};
+use history::{BoardChange, BoardHistory};
+use initiative::{Encounter, InitiativeEntry, InitiativeSlot, Turn};
+use limits::{ConnectionLimit, MessageKind, RateLimiter, Verdict};
+use map::MapDefinition;
+use outbound::{OutboundQueue, QueueError};
+use pathfinding::{MovementUsed, Occupancy, PassThrough, Path, PathError};
+use protocol::{ClientMessage, ErrorCode, ServerMessage, StateUpdate};
+use room::{Room, Rooms};
+use shutdown::ShutdownFlag;
+use token::{ActiveCondition, Condition, Faction, HitPoints, NpcChanges, NpcToken, TokenSize, TokenStatus};
+use visibility::Viewer;
+
 type ClientId = String;
-type ClientToPlayerMap = Arc<RwLock<HashMap<String, String>>>; // client_id -> player_id
+type ClientSender = futures::stream::SplitSink<warp::ws::WebSocket, warp::ws::Message>;
+type Clients = Arc<RwLock<HashMap<ClientId, Arc<OutboundQueue>>>>;
+type ClientToPlayerMap = Arc<RwLock<HashMap<ClientId, ClientSession>>>;
 
-#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
-struct Position {
-    x: i32,
-    y: i32,
+/// The player identity a connection is bound to, set when it joins or reconnects.
+#[derive(Debug, Clone)]
+pub struct ClientSession {
+    player_id: String,
+    role: Role,
+    permissions: Permissions,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-struct PlayerMove {
-    #[serde(rename = "type")]
-    message_type: String,
-    player_id: String,
-    position: Position,
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    Player,
+    GameMaster,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-struct GameMessage {
-    #[serde(rename = "type")]
-    message_type: String,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    player_id: Option<String>,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    player_name: Option<String>,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    color: Option<String>,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    position: Option<Position>,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    data: Option<serde_json::Value>,
+/// Actions a connection may take beyond controlling its own player.
+#[derive(Debug, Clone, Copy, Default)]
+struct Permissions {
+    /// Allows moving tokens owned by other players.
+    move_any_token: bool,
+    /// Allows moving while movement is locked.
+    ignore_movement_lock: bool,
+    /// Moves by this connection are not charged against movement budgets.
+    ignore_movement_budget: bool,
+    /// Allows moving tokens out of turn during an encounter.
+    ignore_turn_order: bool,
+}
+
+impl Permissions {
+    fn for_role(role: Role) -> Self {
+        match role {
+            Role::Player => Self::default(),
+            Role::GameMaster => Self {
+                move_any_token: true,
+                ignore_movement_lock: true,
+                ignore_movement_budget: true,
+                ignore_turn_order: true,
+            },
+        }
+    }
+}
+
+impl ClientSession {
+    fn new(player_id: String, role: Role) -> Self {
+        Self {
+            player_id,
+            role,
+            permissions: Permissions::for_role(role),
+        }
+    }
+
+    fn is_game_master(&self) -> bool {
+        self.role == Role::GameMaster
+    }
+}
+
+/// Close code sent to connections whose player was kicked (4000-4999 is reserved for applications).
+const CLOSE_CODE_KICKED: u16 = 4001;
+/// Close code sent to connections that kept exceeding their rate limit (policy violation).
+const CLOSE_CODE_RATE_LIMITED: u16 = 1008;
+/// Close code sent to connections whose room could not be opened because too many are (try again later).
+const CLOSE_CODE_TOO_MANY_ROOMS: u16 = 1013;
+
+/// How many recent state updates each room keeps for `resync`.
+const STATE_HISTORY_LEN: usize = 256;
+
+/// How many dice rolls each room keeps in its roll log.
+const ROLL_LOG_LEN: usize = 200;
+
+/// How many chat messages each room keeps for newly joining clients.
+const CHAT_HISTORY_LEN: usize = 100;
+
+/// Roll labels longer than this are cut short.
+const MAX_ROLL_LABEL_LEN: usize = 100;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Position {
+    pub x: i32,
+    pub y: i32,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
-struct PlayerInfo {
+pub struct PlayerInfo {
     name: String,
     color: String,
+    #[serde(default)]
+    size: TokenSize,
     position: Position,
     online: bool,
+    #[serde(flatten)]
+    status: TokenStatus,
 }
 
-struct GameState {
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GameState {
+    map: MapDefinition,
     player_positions: HashMap<String, Position>,
     player_info: HashMap<String, PlayerInfo>,
+    /// Non-player tokens; their positions are also kept in `player_positions`.
+    #[serde(default)]
+    npcs: HashMap<String, NpcToken>,
+    #[serde(default)]
+    reconnect_tokens: HashMap<String, String>, // player_id -> secret reconnect token
+    #[serde(default)]
+    game_masters: HashSet<String>,
+    #[serde(default)]
+    movement_locked: bool,
+    #[serde(default)]
+    movement_used: HashMap<String, MovementUsed>,
+    #[serde(default)]
+    encounter: Option<Encounter>,
+    #[serde(default)]
+    roll_log: VecDeque<DiceRoll>,
+    #[serde(default)]
+    chat_history: VecDeque<ChatMessage>,
+    /// Bumped on every accepted change; persisted so versions stay monotonic across restarts.
+    #[serde(default)]
+    version: u64,
+    #[serde(skip)]
+    history: VecDeque<StateUpdate>,
+    /// Where accepted changes are written; `None` when the event log is disabled or replaying.
+    #[serde(skip)]
+    event_log: Option<RoomLog>,
+    /// Board changes the game master can undo and redo; not persisted.
+    #[serde(skip)]
+    board_history: BoardHistory,
+}
+
+/// The turn passing to a token.
+#[derive(Debug, Clone)]
+struct TurnStart {
+    player_id: String,
+    round: u32,
+    /// Whether any of the token's timed conditions counted down.
+    conditions_ticked: bool,
 }
 
 impl GameState {
-    fn new() -> Self {
+    fn new(map: MapDefinition) -> Self {
         Self {
+            map,
             player_positions: HashMap::new(),
             player_info: HashMap::new(),
+            npcs: HashMap::new(),
+            reconnect_tokens: HashMap::new(),
+            game_masters: HashSet::new(),
+            movement_locked: false,
+            movement_used: HashMap::new(),
+            encounter: None,
+            roll_log: VecDeque::new(),
+            chat_history: VecDeque::new(),
+            version: 0,
+            history: VecDeque::new(),
+            event_log: None,
+            board_history: BoardHistory::default(),
+        }
+    }
+
+    fn attach_event_log(&mut self, event_log: RoomLog) {
+        self.event_log = Some(event_log);
+    }
+
+    fn log_event(&self, event: GameEvent) {
+        if let Some(event_log) = &self.event_log {
+            event_log.append(event);
+        }
+    }
+
+    /// Replays a logged change through the same methods that made it.
+    fn apply(&mut self, event: GameEvent) {
+        match event {
+            GameEvent::RoomCreated { map } => *self = GameState::new(*map),
+            GameEvent::RoomRestored { state } => *self = *state,
+            // Dropping the room is up to whoever holds it
+            GameEvent::RoomClosed => {}
+            GameEvent::PlayerAdded { player_id, name, color, size, position } => {
+                self.add_player_info(player_id, name, color, size, position)
+            }
+            GameEvent::GameMasterGranted { player_id } => self.grant_game_master(&player_id),
+            GameEvent::ReconnectTokenIssued { player_id, token } => self.set_reconnect_token(&player_id, token),
+            GameEvent::PlayerOnline { player_id, online: true } => self.set_player_online(&player_id),
+            GameEvent::PlayerOnline { player_id, online: false } => self.set_player_offline(&player_id),
+            GameEvent::AllPlayersOffline => self.set_all_players_offline(),
+            GameEvent::PlayerRemoved { player_id } => {
+                self.remove_player(&player_id);
+            }
+            GameEvent::TokenMoved { token_id, position } => self.update_player_position(token_id, position),
+            GameEvent::MovementSpent { token_id, used } => self.set_movement_used(&token_id, used),
+            GameEvent::MovementLocked { locked } => self.set_movement_locked(locked),
+            GameEvent::MovementReset => self.reset_movement(),
+            GameEvent::BoardReset => self.reset_board(),
+            GameEvent::DoorSet { position, open } => {
+                self.set_door(&position, open);
+            }
+            GameEvent::EncounterStarted { restrict_movement } => self.start_encounter(restrict_movement),
+            GameEvent::InitiativeSubmitted { token_id, initiative } => {
+                self.submit_initiative(&token_id, initiative);
+            }
+            GameEvent::TurnAdvanced => {
+                self.next_turn();
+            }
+            GameEvent::InitiativeRestored { slot } => {
+                self.restore_initiative(slot);
+            }
+            GameEvent::EncounterEnded => self.end_encounter(),
+            GameEvent::NpcAdded { token_id, token } => self.add_npc(token_id, *token),
+            GameEvent::NpcUpdated { token_id, changes } => {
+                self.update_npc(&token_id, changes);
+            }
+            GameEvent::NpcRemoved { token_id } => {
+                self.remove_npc(&token_id);
+            }
+            GameEvent::StatusUpdated { token_id, hp, add_conditions, remove_conditions } => {
+                self.update_status(&token_id, hp, add_conditions, remove_conditions);
+            }
+            GameEvent::StatusSet { token_id, status } => {
+                self.set_status(&token_id, status);
+            }
+            GameEvent::TokenResized { token_id, size } => {
+                self.set_token_size(&token_id, size);
+            }
+            GameEvent::RollLogged(roll) => self.log_roll(*roll),
+            GameEvent::ChatLogged(message) => self.log_chat(*message),
+            // The message behind the bump went to clients and is not needed to rebuild the state
+            GameEvent::VersionBumped { version } => self.version = version,
+        }
+    }
+
+    fn version(&self) -> u64 {
+        self.version
+    }
+
+    /// Bumps the state version and remembers `message` as the delta that produced it.
+    fn record(&mut self, message: ServerMessage) -> StateUpdate {
+        self.version += 1;
+        self.log_event(GameEvent::VersionBumped { version: self.version });
+        let update = StateUpdate {
+            version: self.version,
+            message,
+        };
+        if self.history.len() == STATE_HISTORY_LEN {
+            self.history.pop_front();
+        }
+        self.history.push_back(update.clone());
+        update
+    }
+
+    /// Stamps `message` with the current version without changing it.
+    fn stamp(&self, message: ServerMessage) -> StateUpdate {
+        StateUpdate {
+            version: self.version,
+            message,
+        }
+    }
+
+    /// Returns the updates after `since_version`, or `None` if some of them are
+    /// no longer retained (or the version is from the future) and the client
+    /// needs a full snapshot instead.
+    fn updates_since(&self, since_version: u64) -> Option<Vec<StateUpdate>> {
+        if since_version > self.version {
+            return None;
+        }
+        if since_version == self.version {
+            return Some(Vec::new());
+        }
+        match self.history.front() {
+            Some(oldest) if oldest.version <= since_version + 1 => Some(
+                self.history
+                    .iter()
+                    .filter(|update| update.version > since_version)
+                    .cloned()
+                    .collect(),
+            ),
+            _ => None,
+        }
+    }
+
+    fn game_state_message(&self) -> ServerMessage {
+        ServerMessage::GameState {
+            data: self.player_info.clone(),
+            movement_locked: self.movement_locked,
+            encounter: self.encounter.clone().map(Box::new),
+            npcs: self.npcs.clone(),
         }
     }
 
+    /// The full game state stamped with the current version.
+    fn snapshot(&self) -> StateUpdate {
+        self.stamp(self.game_state_message())
+    }
+
+    fn map_state_message(&self) -> ServerMessage {
+        ServerMessage::MapState {
+            map: Box::new(self.map.clone()),
+        }
+    }
+
+    /// Records a change to the map.
+    fn record_map_state(&mut self) -> StateUpdate {
+        let message = self.map_state_message();
+        self.record(message)
+    }
+
+    /// Records a change that is best described by the whole game state.
+    fn record_snapshot(&mut self) -> StateUpdate {
+        let message = self.game_state_message();
+        self.record(message)
+    }
+
+    fn map(&self) -> &MapDefinition {
+        &self.map
+    }
+
+    /// Opens or closes a door, returning false if there is no door at `position`.
+    fn set_door(&mut self, position: &Position, open: bool) -> bool {
+        let changed = self.map.set_door(position, open);
+        if changed {
+            info!("Door at ({}, {}) {}", position.x, position.y, if open { "opened" } else { "closed" });
+            self.log_event(GameEvent::DoorSet { position: *position, open });
+        }
+        changed
+    }
+
     fn update_player_position(&mut self, player_id: String, position: Position) {
-        let player_id_clone = player_id.clone();
-        let position_clone = position.clone();
-        self.player_positions.insert(player_id.clone(), position);
-        
         // Update position in player_info if it exists
         if let Some(player_info) = self.player_info.get_mut(&player_id) {
             player_info.position = position;
         }
-        
-        info!("Updated position for player {}: ({}, {})", player_id_clone, position_clone.x, position_clone.y);
+        if let Some(token) = self.npcs.get_mut(&player_id) {
+            token.position = position;
+        }
+
+        info!("Updated position for player {}: ({}, {})", player_id, position.x, position.y);
+        self.player_positions.insert(player_id.clone(), position);
+        self.log_event(GameEvent::TokenMoved {
+            token_id: player_id,
+            position,
+        });
     }
 
-    fn add_player_info(&mut self, player_id: String, name: String, color: String, position: Position) {
-        let position_clone1 = position.clone();
-        let position_clone2 = position.clone();
+    fn add_player_info(&mut self, player_id: String, name: String, color: String, size: TokenSize, position: Position) {
         let player_info = PlayerInfo {
             name: name.clone(),
             color: color.clone(),
-            position: position_clone1,
+            size,
+            position,
             online: true, // Default to online
+            status: TokenStatus::default(),
         };
         self.player_info.insert(player_id.clone(), player_info);
-        self.player_positions.insert(player_id.clone(), position_clone2);
+        self.player_positions.insert(player_id.clone(), position);
         info!("Added player info for {}: name={}, color={}", player_id, name, color);
+        self.log_event(GameEvent::PlayerAdded {
+            player_id,
+            name,
+            color,
+            size,
+            position,
+        });
     }
 
     fn set_player_offline(&mut self, player_id: &str) {
         if let Some(player_info) = self.player_info.get_mut(player_id) {
             player_info.online = false;
             info!("Set player {} offline", player_id);
+            self.log_event(GameEvent::PlayerOnline {
+                player_id: player_id.to_string(),
+                online: false,
+            });
+        }
+    }
+
+    fn set_all_players_offline(&mut self) {
+        for player_info in self.player_info.values_mut() {
+            player_info.online = false;
         }
+        self.log_event(GameEvent::AllPlayersOffline);
     }
 
     fn find_player_by_name(&self, name: &str) -> Option<&String> {
@@ -108,483 +439,2069 @@ impl GameState {
         None
     }
 
-    fn update_player_id(&mut self, old_player_id: &str, new_player_id: String) {
-        if let Some(player_info) = self.player_info.remove(old_player_id) {
-            let player_name = player_info.name.clone();
-            let mut updated_player_info = player_info;
-            updated_player_info.online = true;
-            self.player_info.insert(new_player_id.clone(), updated_player_info);
-            
-            // Update position mapping
-            if let Some(position) = self.player_positions.remove(old_player_id) {
-                self.player_positions.insert(new_player_id.clone(), position);
-            }
-            
-            info!("Updated player ID from {} to {} for player {}", old_player_id, new_player_id, player_name);
+    fn set_player_online(&mut self, player_id: &str) {
+        if let Some(player_info) = self.player_info.get_mut(player_id) {
+            player_info.online = true;
+            info!("Set player {} online", player_id);
+            self.log_event(GameEvent::PlayerOnline {
+                player_id: player_id.to_string(),
+                online: true,
+            });
         }
     }
 
-    fn remove_player(&mut self, player_id: &str) {
+    /// Generates a new secret token the player must present to reconnect.
+    fn issue_reconnect_token(&mut self, player_id: &str) -> String {
+        let token = Uuid::new_v4().simple().to_string();
+        self.set_reconnect_token(player_id, token.clone());
+        token
+    }
+
+    fn set_reconnect_token(&mut self, player_id: &str, token: String) {
+        self.reconnect_tokens.insert(player_id.to_string(), token.clone());
+        self.log_event(GameEvent::ReconnectTokenIssued {
+            player_id: player_id.to_string(),
+            token,
+        });
+    }
+
+    fn find_player_by_reconnect_token(&self, token: &str) -> Option<&String> {
+        self.reconnect_tokens
+            .iter()
+            .find(|(_, player_token)| player_token.as_str() == token)
+            .map(|(player_id, _)| player_id)
+    }
+
+    /// Removes the player and their token. If it was their turn, the turn
+    /// passes on to the next token, which is returned.
+    fn remove_player(&mut self, player_id: &str) -> Option<TurnStart> {
         self.player_positions.remove(player_id);
         self.player_info.remove(player_id);
+        self.reconnect_tokens.remove(player_id);
+        self.game_masters.remove(player_id);
+        self.movement_used.remove(player_id);
+        self.board_history.forget(player_id);
         info!("Removed player {} from game state", player_id);
+        self.log_event(GameEvent::PlayerRemoved {
+            player_id: player_id.to_string(),
+        });
+        self.leave_encounter(player_id)
     }
 
-    fn get_player_position(&self, player_id: &str) -> Option<&Position> {
-        self.player_positions.get(player_id)
+    /// Takes a token out of the initiative order; if it was their turn, the
+    /// turn passes on to the next token, which is returned.
+    fn leave_encounter(&mut self, token_id: &str) -> Option<TurnStart> {
+        let encounter = self.encounter.as_mut()?;
+        let was_active = encounter.is_turn_of(token_id);
+        encounter.remove(token_id);
+        if !was_active {
+            return None;
+        }
+        let active = encounter.active.clone()?;
+        let round = encounter.round;
+        Some(self.begin_turn(active, round))
     }
 
-    fn get_all_positions(&self) -> &HashMap<String, Position> {
-        &self.player_positions
+    /// Gives the token whose turn starts its movement back and counts down its timed conditions.
+    fn begin_turn(&mut self, player_id: String, round: u32) -> TurnStart {
+        self.movement_used.remove(&player_id);
+        let conditions_ticked = self.token_status_mut(&player_id).is_some_and(|status| status.tick());
+        if conditions_ticked {
+            info!("Counted down conditions on token {}", player_id);
+        }
+        TurnStart {
+            player_id,
+            round,
+            conditions_ticked,
+        }
     }
 
-    fn get_all_player_info(&self) -> &HashMap<String, PlayerInfo> {
-        &self.player_info
+    /// Places a non-player token, replacing any token with the same id.
+    fn add_npc(&mut self, token_id: String, token: NpcToken) {
+        info!("Added {} token '{}' at ({}, {})", token_id, token.name, token.position.x, token.position.y);
+        self.player_positions.insert(token_id.clone(), token.position);
+        self.npcs.insert(token_id.clone(), token.clone());
+        self.log_event(GameEvent::NpcAdded {
+            token_id,
+            token: Box::new(token),
+        });
     }
 
-    fn get_online_players(&self) -> HashMap<String, PlayerInfo> {
-        self.player_info.iter()
-            .filter(|(_, player_info)| player_info.online)
-            .map(|(player_id, player_info)| (player_id.clone(), player_info.clone()))
-            .collect()
+    /// Applies `changes` to a non-player token and returns it, or `None` if there is no such token.
+    fn update_npc(&mut self, token_id: &str, changes: NpcChanges) -> Option<NpcToken> {
+        let token = self.npcs.get_mut(token_id)?;
+        token.apply(changes.clone());
+        let token = token.clone();
+        info!("Updated token {}", token_id);
+        self.log_event(GameEvent::NpcUpdated {
+            token_id: token_id.to_string(),
+            changes,
+        });
+        Some(token)
     }
-}
 
-type SharedGameState = Arc<RwLock<GameState>>;
+    /// Removes a non-player token; like `remove_player`, returns the new turn if it was active.
+    fn remove_npc(&mut self, token_id: &str) -> Option<TurnStart> {
+        self.npcs.remove(token_id);
+        self.player_positions.remove(token_id);
+        self.movement_used.remove(token_id);
+        info!("Removed token {}", token_id);
+        self.log_event(GameEvent::NpcRemoved {
+            token_id: token_id.to_string(),
+        });
+        self.leave_encounter(token_id)
+    }
 
-#[tokio::main]
-async fn main() {
-    // Initialize logger
-    env_logger::init();
+    /// Changes a token's hit points and conditions, returning false if there is no such token.
+    fn update_status(&mut self, token_id: &str, hp: Option<HitPoints>, add_conditions: Vec<ActiveCondition>, remove_conditions: Vec<Condition>) -> bool {
+        let Some(status) = self.token_status_mut(token_id) else {
+            return false;
+        };
+        if hp.is_some() {
+            status.hp = hp;
+        }
+        for condition in &remove_conditions {
+            status.remove_condition(*condition);
+        }
+        for active in &add_conditions {
+            status.add_condition(*active);
+        }
+        info!("Updated the status of token {}", token_id);
+        self.log_event(GameEvent::StatusUpdated {
+            token_id: token_id.to_string(),
+            hp,
+            add_conditions,
+            remove_conditions,
+        });
+        true
+    }
 
-    // Get the address to bind to
-    let addr = env::args()
-        .nth(1)
-        .unwrap_or_else(|| "0.0.0.0:8000".to_string());
-    let addr: SocketAddr = addr.parse().expect("Invalid addr");
+    /// Replaces a token's hit points and conditions, returning false if there is no such token.
+    fn set_status(&mut self, token_id: &str, status: TokenStatus) -> bool {
+        let Some(current) = self.token_status_mut(token_id) else {
+            return false;
+        };
+        *current = status.clone();
+        info!("Restored the status of token {}", token_id);
+        self.log_event(GameEvent::StatusSet {
+            token_id: token_id.to_string(),
+            status,
+        });
+        true
+    }
 
-    info!("WebSocket game server starting on: {}", addr);
-    info!("Ready to accept browser connections");
-    info!("Connect from browser using: ws://{}", addr);
+    fn record_board_change(&mut self, change: BoardChange) {
+        self.board_history.record(change);
+    }
 
-    // Shared state for all connected clients and game state
-    let clients: Clients = Arc::new(RwLock::new(HashMap::new()));
-    let game_state: SharedGameState = Arc::new(RwLock::new(GameState::new()));
-    let client_to_player: ClientToPlayerMap = Arc::new(RwLock::new(HashMap::new()));
+    fn get_all_npcs(&self) -> &HashMap<String, NpcToken> {
+        &self.npcs
+    }
 
-    // WebSocket route
-    let ws_route = warp::path("ws")
-        .and(warp::ws())
-        .and(with_clients(clients.clone()))
-        .and(with_game_state(game_state.clone()))
-        .and(with_client_to_player(client_to_player.clone()))
-        .and_then(ws_handler);
+    /// Whether `token_id` names a player or a non-player token.
+    fn has_token(&self, token_id: &str) -> bool {
+        self.player_info.contains_key(token_id) || self.npcs.contains_key(token_id)
+    }
 
-    // Health check route
-    let health_route = warp::path("health")
-        .map(|| "OK");
+    /// Whether the game master has hidden this token from players.
+    fn is_hidden(&self, token_id: &str) -> bool {
+        self.npcs.get(token_id).is_some_and(|token| token.hidden)
+    }
 
-    // Combine routes
-    let routes = ws_route
-        .or(health_route)
-        .with(warp::cors().allow_any_origin());
+    fn has_hidden_tokens(&self) -> bool {
+        self.npcs.values().any(|token| token.hidden)
+    }
 
-    // Start the server
-    warp::serve(routes)
-        .run(addr)
-        .await;
-}
+    /// The name and color of a player or non-player token.
+    fn token_label(&self, token_id: &str) -> Option<(String, String)> {
+        if let Some(player_info) = self.player_info.get(token_id) {
+            return Some((player_info.name.clone(), player_info.color.clone()));
+        }
+        self.npcs.get(token_id).map(|token| (token.name.clone(), token.color.clone()))
+    }
 
-fn with_clients(clients: Clients) -> impl Filter<Extract = (Clients,), Error = std::convert::Infallible> + Clone {
-    warp::any().map(move || clients.clone())
-}
+    fn grant_game_master(&mut self, player_id: &str) {
+        self.game_masters.insert(player_id.to_string());
+        info!("Granted game master role to player {}", player_id);
+        self.log_event(GameEvent::GameMasterGranted {
+            player_id: player_id.to_string(),
+        });
+    }
 
-fn with_game_state(game_state: SharedGameState) -> impl Filter<Extract = (SharedGameState,), Error = std::convert::Infallible> + Clone {
-    warp::any().map(move || game_state.clone())
-}
+    fn player_role(&self, player_id: &str) -> Role {
+        if self.game_masters.contains(player_id) {
+            Role::GameMaster
+        } else {
+            Role::Player
+        }
+    }
 
-fn with_client_to_player(client_to_player: ClientToPlayerMap) -> impl Filter<Extract = (ClientToPlayerMap,), Error = std::convert::Infallible> + Clone {
-    warp::any().map(move || client_to_player.clone())
-}
+    fn is_movement_locked(&self) -> bool {
+        self.movement_locked
+    }
 
-async fn ws_handler(ws: warp::ws::Ws, clients: Clients, game_state: SharedGameState, client_to_player: ClientToPlayerMap) -> Result<impl Reply, Rejection> {
-    info!("New WebSocket connection request");
-    Ok(ws.on_upgrade(move |socket| handle_websocket(socket, clients, game_state, client_to_player)))
-}
+    fn set_movement_locked(&mut self, locked: bool) {
+        self.movement_locked = locked;
+        info!("Movement {}", if locked { "locked" } else { "unlocked" });
+        self.log_event(GameEvent::MovementLocked { locked });
+    }
 
-async fn handle_websocket(ws: warp::ws::WebSocket, clients: Clients, game_state: SharedGameState, client_to_player: ClientToPlayerMap) {
-    info!("WebSocket connection established from browser");
+    fn movement_used(&self, player_id: &str) -> MovementUsed {
+        self.movement_used.get(player_id).copied().unwrap_or_default()
+    }
 
-    // Generate unique client ID
-    let client_id = Uuid::new_v4().to_string();
-    info!("Client connected with ID: {}", client_id);
+    fn spend_movement(&mut self, player_id: &str, path: &Path) {
+        let mut used = self.movement_used(player_id);
+        used.spend(path);
+        self.set_movement_used(player_id, used);
+    }
 
-    // Split the websocket stream into sender and receiver
-    let (sender, mut receiver) = ws.split();
+    fn set_movement_used(&mut self, player_id: &str, used: MovementUsed) {
+        self.movement_used.insert(player_id.to_string(), used);
+        self.log_event(GameEvent::MovementSpent {
+            token_id: player_id.to_string(),
+            used,
+        });
+    }
 
-    // Add client to the shared state
-    {
-        let mut clients_lock = clients.write().await;
-        clients_lock.insert(client_id.clone(), sender);
-        info!("Total connected clients: {}", clients_lock.len());
+    /// Gives every token its full movement budget back.
+    fn reset_movement(&mut self) {
+        self.movement_used.clear();
+        info!("Reset movement budgets");
+        self.log_event(GameEvent::MovementReset);
     }
 
-    // Send current game state to the new client
-    send_game_state_to_client(&clients, &game_state, &client_id).await;
+    fn encounter(&self) -> Option<&Encounter> {
+        self.encounter.as_ref()
+    }
 
-    // Broadcast new client connection to all other clients
-    broadcast_client_connected(&clients, &client_id).await;
+    fn start_encounter(&mut self, restrict_movement: bool) {
+        self.encounter = Some(Encounter::new(restrict_movement));
+        self.movement_used.clear();
+        info!("Encounter started");
+        self.log_event(GameEvent::EncounterStarted { restrict_movement });
+    }
 
-    // Handle incoming messages
-    while let Some(result) = receiver.next().await {
-        match result {
-            Ok(msg) => {
-                if msg.is_text() {
-                    let text = msg.to_str().unwrap_or("Invalid UTF-8");
-                    info!("Received message from client {}: {}", client_id, text);
-                    
-                    // Try to parse as game message
-                    if let Ok(game_msg) = serde_json::from_str::<GameMessage>(text) {
-                        handle_game_message(&clients, &game_state, &client_to_player, &client_id, game_msg).await;
-                    } else {
-                        // Fallback to regular broadcast for non-game messages
-                        broadcast_message(&clients, &client_id, text).await;
-                    }
-                } else if msg.is_binary() {
-                    let data = msg.as_bytes();
-                    info!("Received binary message with {} bytes from client {}", data.len(), client_id);
-                    
-                    // Broadcast binary data to all clients
-                    broadcast_binary(&clients, &client_id, data).await;
-                } else if msg.is_ping() {
-                    info!("Received ping from client {}, sending pong", client_id);
-                    if let Err(e) = send_to_client(&clients, &client_id, Message::pong(msg.as_bytes())).await {
-                        error!("Error sending pong to client {}: {}", client_id, e);
-                        break;
-                    }
-                } else if msg.is_pong() {
-                    info!("Received pong from client {}", client_id);
-                } else if msg.is_close() {
-                    info!("Client {} disconnected", client_id);
-                    break;
-                }
-            }
-            Err(e) => {
-                error!("WebSocket error from client {}: {}", client_id, e);
-                break;
-            }
-        }
+    fn end_encounter(&mut self) {
+        self.encounter = None;
+        info!("Encounter ended");
+        self.log_event(GameEvent::EncounterEnded);
     }
 
-    // Set player offline if they were registered
-    {
-        let mut client_to_player_lock = client_to_player.write().await;
-        if let Some(player_id) = client_to_player_lock.remove(&client_id) {
-            let mut game_state_lock = game_state.write().await;
-            game_state_lock.set_player_offline(&player_id);
-            
-            // Broadcast updated game state to all remaining clients
-            let updated_player_info = game_state_lock.get_all_player_info().clone();
-            drop(game_state_lock); // Release lock before broadcasting
-            
-            if !updated_player_info.is_empty() {
-                let game_state_message = GameMessage {
-                    message_type: "game_state".to_string(),
-                    player_id: None,
-                    player_name: None,
-                    color: None,
-                    position: None,
-                    data: Some(serde_json::to_value(updated_player_info).unwrap_or_default()),
-                };
+    /// Records `player_id`'s initiative and returns the new order, or `None` outside an encounter.
+    fn submit_initiative(&mut self, player_id: &str, initiative: i32) -> Option<Vec<InitiativeEntry>> {
+        let encounter = self.encounter.as_mut()?;
+        encounter.submit(player_id, initiative);
+        info!("Player {} rolled {} for initiative", player_id, initiative);
+        let order = encounter.order.clone();
+        self.log_event(GameEvent::InitiativeSubmitted {
+            token_id: player_id.to_string(),
+            initiative,
+        });
+        Some(order)
+    }
 
-                if let Ok(msg_str) = serde_json::to_string(&game_state_message) {
-                    info!("Broadcasting updated game state after player {} went offline: {}", player_id, msg_str);
-                    broadcast_message(&clients, &client_id, &msg_str).await;
-                }
-            }
-        }
+    /// Passes the turn on, returning the new active token, or `None` if nobody has rolled initiative.
+    fn next_turn(&mut self) -> Option<TurnStart> {
+        let encounter = self.encounter.as_mut()?;
+        let player_id = encounter.advance()?.to_string();
+        let round = encounter.round;
+        info!("Round {}: turn of player {}", round, player_id);
+        self.log_event(GameEvent::TurnAdvanced);
+        Some(self.begin_turn(player_id, round))
     }
 
-    // Broadcast client disconnection to remaining clients
-    broadcast_client_disconnected(&clients, &client_id).await;
+    /// Records a change of turn, followed by a `token_updated` if the token's
+    /// timed conditions counted down.
+    /// Puts a token back into the encounter after its removal is undone,
+    /// returning its turn if it gets that back too.
+    fn restore_initiative(&mut self, slot: InitiativeSlot) -> Option<TurnStart> {
+        let encounter = self.encounter.as_mut()?;
+        let player_id = slot.entry.player_id.clone();
+        let turn_restored = encounter.restore(slot.clone());
+        let round = encounter.round;
+        info!("Put token {} back into the initiative order", player_id);
+        self.log_event(GameEvent::InitiativeRestored { slot });
+        turn_restored.then_some(TurnStart {
+            player_id,
+            round,
+            conditions_ticked: false,
+        })
+    }
 
-    // Remove client from the shared state
-    {
-        let mut clients_lock = clients.write().await;
-        clients_lock.remove(&client_id);
-        info!("Client {} disconnected. Total connected clients: {}", client_id, clients_lock.len());
+    fn current_turn(&self) -> Option<Turn> {
+        self.encounter()?.turn()
     }
 
-    info!("WebSocket connection closed for client {}", client_id);
-}
+    fn record_turn_change(&mut self, turn: TurnStart) -> Vec<StateUpdate> {
+        let mut updates = vec![self.record(ServerMessage::TurnChanged {
+            player_id: turn.player_id.clone(),
+            round: turn.round,
+        })];
+        if turn.conditions_ticked {
+            if let Some(message) = self.token_updated_message(&turn.player_id) {
+                updates.push(self.record(message));
+            }
+        }
+        updates
+    }
 
-async fn handle_game_message(clients: &Clients, game_state: &SharedGameState, client_to_player: &ClientToPlayerMap, sender_id: &str, game_msg: GameMessage) {
-    match game_msg.message_type.as_str() {
-        "player_move" => {
-            // Clone the message before moving its fields
-            let game_msg_clone = game_msg.clone();
-            
-            if let (Some(player_id), Some(position)) = (game_msg.player_id, game_msg.position) {
-                info!("Processing player_move from {}: ({}, {})", player_id, position.x, position.y);
-                
-                // Track the player_id for this client
-                {
-                    let mut client_to_player_lock = client_to_player.write().await;
-                    client_to_player_lock.insert(sender_id.to_string(), player_id.clone());
-                }
-                
-                // Update game state with new position
-                {
-                    let mut state_lock = game_state.write().await;
-                    state_lock.update_player_position(player_id.clone(), position.clone());
-                }
-
-                // Broadcast the original message to all other clients
-                if let Ok(msg_str) = serde_json::to_string(&game_msg_clone) {
-                    info!("Broadcasting player_move to other clients: {}", msg_str);
-                    broadcast_message(clients, sender_id, &msg_str).await;
-                } else {
-                    error!("Failed to serialize player_move message for broadcast");
-                }
-            } else {
-                error!("Invalid player_move message: missing player_id or position");
-            }
-        }
-        "player_join" => {
-            if let (Some(player_id), Some(player_name), Some(color)) = (game_msg.player_id, game_msg.player_name, game_msg.color) {
-                info!("Player {} joining the game with name '{}' and color '{}'", player_id, player_name, color);
-                
-                // Track the player_id for this client
-                {
-                    let mut client_to_player_lock = client_to_player.write().await;
-                    client_to_player_lock.insert(sender_id.to_string(), player_id.clone());
-                }
-                
-                // Check if player with this name already exists
-                let existing_player_id = {
-                    let state_lock = game_state.read().await;
-                    state_lock.find_player_by_name(&player_name).cloned()
-                };
-                
-                if let Some(existing_id) = existing_player_id {
-                    // Player exists, update their ID and set them online
-                    {
-                        let mut state_lock = game_state.write().await;
-                        state_lock.update_player_id(&existing_id, player_id.clone());
-                    }
-                    
-                    info!("Player '{}' reconnected with new ID: {}", player_name, player_id);
-                    
-                    // Broadcast player reconnection to all other clients
-                    let reconnect_message = GameMessage {
-                        message_type: "player_reconnect".to_string(),
-                        player_id: Some(player_id.clone()),
-                        player_name: Some(player_name.clone()),
-                        color: Some(color.clone()),
-                        position: None,
-                        data: None,
-                    };
+    fn token_status_mut(&mut self, token_id: &str) -> Option<&mut TokenStatus> {
+        match self.player_info.get_mut(token_id) {
+            Some(player_info) => Some(&mut player_info.status),
+            None => self.npcs.get_mut(token_id).map(|token| &mut token.status),
+        }
+    }
 
-                    if let Ok(msg_str) = serde_json::to_string(&reconnect_message) {
-                        info!("Broadcasting player reconnection to other clients: {}", msg_str);
-                        broadcast_message(clients, sender_id, &msg_str).await;
-                    }
-                } else {
-                    // New player, add them to game state
-                    {
-                        let mut state_lock = game_state.write().await;
-                        state_lock.add_player_info(player_id.clone(), player_name.clone(), color.clone(), Position { x: 0, y: 0 });
-                    }
-                    
-                    // Broadcast player join to all other clients
-                    let join_message = GameMessage {
-                        message_type: "player_join".to_string(),
-                        player_id: Some(player_id.clone()),
-                        player_name: Some(player_name.clone()),
-                        color: Some(color.clone()),
-                        position: None,
-                        data: None,
-                    };
+    fn token_status(&self, token_id: &str) -> Option<&TokenStatus> {
+        match self.player_info.get(token_id) {
+            Some(player_info) => Some(&player_info.status),
+            None => self.npcs.get(token_id).map(|token| &token.status),
+        }
+    }
 
-                    if let Ok(msg_str) = serde_json::to_string(&join_message) {
-                        info!("Broadcasting player join to other clients: {}", msg_str);
-                        broadcast_message(clients, sender_id, &msg_str).await;
-                    }
-                }
-                
-                // Get all current player info and send to the new player
-                let player_info = {
-                    let state_lock = game_state.read().await;
-                    state_lock.get_all_player_info().clone()
-                };
+    fn token_updated_message(&self, token_id: &str) -> Option<ServerMessage> {
+        let status = self.token_status(token_id)?;
+        Some(ServerMessage::TokenUpdated {
+            token_id: token_id.to_string(),
+            hp: status.hp,
+            conditions: status.conditions.clone(),
+            size: self.token_size(token_id),
+        })
+    }
 
-                // Send each player's info as a separate player_move message
-                for (existing_player_id, existing_player_info) in player_info {
-                    if existing_player_id != player_id {
-                        let move_message = GameMessage {
-                            message_type: "player_move".to_string(),
-                            player_id: Some(existing_player_id),
-                            player_name: Some(existing_player_info.name),
-                            color: Some(existing_player_info.color),
-                            position: Some(existing_player_info.position),
-                            data: None,
-                        };
-
-                        if let Ok(msg_str) = serde_json::to_string(&move_message) {
-                            info!("Sending player_move to joining player {}: {}", player_id, msg_str);
-                            if let Err(e) = send_to_client(clients, sender_id, Message::text(msg_str)).await {
-                                error!("Error sending player_move to joining client {}: {}", sender_id, e);
-                            }
-                        }
-                    }
-                }
-            } else {
-                error!("Invalid player_join message: missing player_id, player_name, or color");
-            }
+    fn log_roll(&mut self, roll: DiceRoll) {
+        if self.roll_log.len() == ROLL_LOG_LEN {
+            self.roll_log.pop_front();
         }
-        "get_positions" => {
-            // Send current positions to the requesting client
-            let positions = {
-                let state_lock = game_state.read().await;
-                state_lock.get_all_positions().clone()
-            };
+        self.roll_log.push_back(roll.clone());
+        self.log_event(GameEvent::RollLogged(Box::new(roll)));
+    }
 
-            let response = GameMessage {
-                message_type: "positions_update".to_string(),
-                player_id: None,
-                player_name: None,
-                color: None,
-                position: None,
-                data: Some(serde_json::to_value(positions).unwrap_or_default()),
-            };
+    /// The roll log as `player_id` may see it: public rolls plus their own
+    /// secret ones, or everything for the game master.
+    fn roll_log_for(&self, player_id: &str, is_game_master: bool) -> Vec<DiceRoll> {
+        self.roll_log
+            .iter()
+            .filter(|roll| !roll.secret || is_game_master || roll.player_id == player_id)
+            .cloned()
+            .collect()
+    }
 
-            if let Ok(response_str) = serde_json::to_string(&response) {
-                if let Err(e) = send_to_client(clients, sender_id, Message::text(response_str)).await {
-                    error!("Error sending positions to client {}: {}", sender_id, e);
-                }
-            }
-        }
-        _ => {
-            // Broadcast all other game messages to all other clients
-            if let Ok(msg_str) = serde_json::to_string(&game_msg) {
-                info!("Broadcasting game message to other clients: {}", msg_str);
-                broadcast_message(clients, sender_id, &msg_str).await;
-            }
+    fn log_chat(&mut self, message: ChatMessage) {
+        if self.chat_history.len() == CHAT_HISTORY_LEN {
+            self.chat_history.pop_front();
         }
+        self.chat_history.push_back(message.clone());
+        self.log_event(GameEvent::ChatLogged(Box::new(message)));
     }
-}
 
-async fn broadcast_message(clients: &Clients, sender_id: &str, message: &str) {
-    let mut clients_lock = clients.write().await;
-    let mut disconnected_clients = Vec::new();
-    let mut broadcast_count = 0;
+    /// Logs a server-generated chat line and returns it for delivery.
+    fn system_line(&mut self, text: String) -> ChatMessage {
+        let message = ChatMessage::system(text, unix_time());
+        self.log_chat(message.clone());
+        message
+    }
 
-    info!("Broadcasting message to {} clients (excluding sender {})", clients_lock.len(), sender_id);
+    fn chat_history_for(&self, session: Option<&ClientSession>) -> Vec<ChatMessage> {
+        self.chat_history
+            .iter()
+            .filter(|message| message.is_visible_to(session))
+            .cloned()
+            .collect()
+    }
 
-    for (client_id, sender) in clients_lock.iter_mut() {
-        if client_id != sender_id {
-            // Don't send back to the sender
-            if let Err(e) = sender.send(Message::text(message.to_string())).await {
-                error!("Error broadcasting message to client {}: {}", client_id, e);
-                disconnected_clients.push(client_id.clone());
-            } else {
-                broadcast_count += 1;
-                info!("Successfully broadcasted to client {}", client_id);
+    /// Cells covered by every token other than `mover`, and which of them
+    /// `mover` may not walk through. Without a mover, every token blocks.
+    fn occupancy(&self, mover: Option<&str>, pass_through: PassThrough) -> Occupancy {
+        let mover_faction = mover.map(|mover| self.token_faction(mover));
+        let mut occupancy = Occupancy::default();
+        for (token_id, position) in &self.player_positions {
+            if Some(token_id.as_str()) == mover {
+                continue;
+            }
+            let passable = match mover_faction {
+                Some(faction) if faction == self.token_faction(token_id) => pass_through.allies,
+                Some(_) => pass_through.enemies,
+                None => false,
+            };
+            for cell in self.token_size(token_id).footprint(*position) {
+                occupancy.cells.insert(cell);
+                if !passable {
+                    occupancy.blocking.insert(cell);
+                }
             }
         }
+        occupancy
     }
 
-    info!("Broadcasted message to {} clients", broadcast_count);
+    fn token_size(&self, token_id: &str) -> TokenSize {
+        match self.player_info.get(token_id) {
+            Some(player_info) => player_info.size,
+            None => self.npcs.get(token_id).map(|token| token.size).unwrap_or_default(),
+        }
+    }
 
-    // Clean up disconnected clients
-    for client_id in disconnected_clients {
-        clients_lock.remove(&client_id);
-        info!("Removed disconnected client: {}", client_id);
+    /// Changes a token's size, returning false if there is no such token.
+    fn set_token_size(&mut self, token_id: &str, size: TokenSize) -> bool {
+        if let Some(player_info) = self.player_info.get_mut(token_id) {
+            player_info.size = size;
+        } else if let Some(token) = self.npcs.get_mut(token_id) {
+            token.size = size;
+        } else {
+            return false;
+        }
+        info!("Resized token {} to {:?}", token_id, size);
+        self.log_event(GameEvent::TokenResized {
+            token_id: token_id.to_string(),
+            size,
+        });
+        true
     }
-}
 
-async fn broadcast_binary(clients: &Clients, sender_id: &str, data: &[u8]) {
-    let mut clients_lock = clients.write().await;
-    let mut disconnected_clients = Vec::new();
+    /// Players are always in the party; non-player tokens pick a side.
+    fn token_faction(&self, token_id: &str) -> Faction {
+        match self.npcs.get(token_id) {
+            Some(token) => token.faction,
+            None => Faction::Party,
+        }
+    }
 
-    for (client_id, sender) in clients_lock.iter_mut() {
-        if client_id != sender_id {
-            // Don't send back to the sender
-            if let Err(e) = sender.send(Message::binary(data)).await {
-                error!("Error broadcasting binary to client {}: {}", client_id, e);
-                disconnected_clients.push(client_id.clone());
+    /// Sends every player back to the spawn, each on the nearest cell left free, and unlocks movement.
+    /// Players are placed in id order so replaying the event log places them alike.
+    fn reset_board(&mut self) {
+        let spawn = self.map.spawn;
+        let mut occupied: HashSet<Position> = self
+            .player_positions
+            .iter()
+            .filter(|(token_id, _)| !self.player_info.contains_key(*token_id))
+            .flat_map(|(token_id, position)| self.token_size(token_id).footprint(*position))
+            .collect();
+        let mut player_ids: Vec<String> = self.player_info.keys().cloned().collect();
+        player_ids.sort();
+        for player_id in player_ids {
+            let size = self.token_size(&player_id);
+            // With no room left the player stacks on the spawn rather than leave the board
+            let position = pathfinding::nearest_free_cell(&self.map, spawn, size, &occupied).unwrap_or(spawn);
+            occupied.extend(size.footprint(position));
+            if let Some(player_info) = self.player_info.get_mut(&player_id) {
+                player_info.position = position;
             }
+            self.player_positions.insert(player_id, position);
         }
+        self.movement_locked = false;
+        self.movement_used.clear();
+        self.board_history.clear();
+        info!("Reset board for {} players", self.player_info.len());
+        self.log_event(GameEvent::BoardReset);
     }
 
-    // Clean up disconnected clients
-    for client_id in disconnected_clients {
-        clients_lock.remove(&client_id);
-        info!("Removed disconnected client: {}", client_id);
+    fn get_player_position(&self, player_id: &str) -> Option<&Position> {
+        self.player_positions.get(player_id)
     }
-}
 
-async fn send_to_client(clients: &Clients, client_id: &str, message: Message) -> Result<(), Box<dyn std::error::Error>> {
-    let mut clients_lock = clients.write().await;
-    if let Some(sender) = clients_lock.get_mut(client_id) {
-        sender.send(message).await?;
-    }
-    Ok(())
+    fn get_all_positions(&self) -> &HashMap<String, Position> {
+        &self.player_positions
+    }
+
+    fn get_all_player_info(&self) -> &HashMap<String, PlayerInfo> {
+        &self.player_info
+    }
+}
+
+type SharedGameState = Arc<RwLock<GameState>>;
+
+#[tokio::main]
+async fn main() {
+    let cli = Cli::parse();
+
+    // `warp-drive replay ...` rebuilds game state from an event log instead of serving
+    if let Some(Command::Replay(args)) = cli.command {
+        env_logger::init();
+        replay::run(args);
+        return;
+    }
+
+    // Settings come from flags, then the environment, then the config file
+    let config = match ServerConfig::load(cli) {
+        Ok(config) => Arc::new(config),
+        Err(e) => startup_error(format!("Invalid configuration: {}", e)),
+    };
+
+    // Initialize logger; RUST_LOG still overrides the configured level
+    env_logger::Builder::new()
+        .filter_level(config.log_level)
+        .parse_env("RUST_LOG")
+        .init();
+
+    let addr = config.bind;
+
+    info!("WebSocket game server starting on: {}", addr);
+    info!("Ready to accept browser connections");
+    info!("Connect from browser using: ws://{}", addr);
+
+    // Every accepted change to any room is appended to the event log
+    let event_log = config.event_log.as_ref().map(|path| {
+        let event_log = EventLog::open(path).unwrap_or_else(|e| startup_error(format!("Failed to open event log {}: {}", path.display(), e)));
+        info!("Logging game events to {}", path.display());
+        Arc::new(event_log)
+    });
+
+    // Registry of game rooms, each with its own clients and game state,
+    // restored from the last snapshot if persistence is enabled
+    let restored_rooms = match &config.state_file {
+        Some(path) => {
+            let snapshot = persistence::load_snapshot(path)
+                .unwrap_or_else(|e| startup_error(format!("Failed to load snapshot from {}: {}", path.display(), e)));
+            info!("Restored {} rooms from {}", snapshot.rooms.len(), path.display());
+            persistence::restore_rooms(snapshot, event_log.clone())
+        }
+        None => HashMap::new(),
+    };
+    let rooms: Rooms = Arc::new(RwLock::new(restored_rooms));
+
+    let snapshot_task = config.state_file.as_ref().map(|path| {
+        let (stop, stopped) = oneshot::channel();
+        let task = tokio::spawn(persistence::run_snapshot_task(path.clone(), rooms.clone(), config.snapshot_interval, stopped));
+        (stop, task)
+    });
+
+    // Closes rooms nobody has come back to, even with players registered
+    tokio::spawn(room::run_idle_room_task(rooms.clone(), config.room_idle_timeout));
+
+    // Caps open connections across all rooms
+    let connection_limit = ConnectionLimit::new(config.max_clients);
+
+    // Raised when a shutdown signal arrives so new upgrades are refused
+    let shutdown_flag: ShutdownFlag = Arc::new(AtomicBool::new(false));
+
+    // WebSocket route: /ws joins the default room, /ws/{room_id} joins a named room
+    let room_id = warp::path::param::<String>()
+        .and(warp::path::end())
+        .or(warp::path::end().map(|| room::DEFAULT_ROOM_ID.to_string()))
+        .unify();
+    let ws_route = warp::path("ws")
+        .and(room_id)
+        .and(warp::ws())
+        .and(warp::header::optional::<String>("origin"))
+        .and(with_rooms(rooms.clone()))
+        .and(with_config(config.clone()))
+        .and(with_shutdown_flag(shutdown_flag.clone()))
+        .and(with_event_log(event_log))
+        .and(with_connection_limit(connection_limit))
+        .and_then(ws_handler);
+
+    // Room listing route
+    let rooms_route = warp::path("rooms")
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(with_rooms(rooms.clone()))
+        .and_then(list_rooms_handler);
+
+    // Health check route
+    let health_route = warp::path("health")
+        .map(|| "OK");
+
+    // Pages on the allowed origins may also call the HTTP routes. WebSocket
+    // upgrades check the origin themselves in ws_handler
+    let cors = if config.allowed_origins.iter().any(|origin| origin == origin::ANY_ORIGIN) {
+        warp::cors().allow_any_origin()
+    } else {
+        // `null` is not an origin warp can match on
+        warp::cors().allow_origins(config.allowed_origins.iter().map(String::as_str).filter(|origin| origin.contains("://")))
+    };
+
+    // Combine routes
+    let routes = ws_route
+        .or(rooms_route.or(health_route).with(cors));
+
+    // Start the server and run until Ctrl+C or SIGTERM, which stops accepting new connections
+    let (_, server) = warp::serve(routes)
+        .bind_with_graceful_shutdown(addr, shutdown::wait_for_signal(shutdown_flag.clone()));
+    server.await;
+
+    // Tell connected clients we're going away, then flush game state before exiting
+    info!("Shutting down, waiting up to {:?} for clients to disconnect", config.shutdown_timeout);
+    shutdown::disconnect_all_clients(&rooms, config.shutdown_timeout).await;
+
+    // Let a periodic save in progress finish first, so the two never write the
+    // temporary snapshot file at once
+    if let Some((stop, task)) = snapshot_task {
+        let _ = stop.send(());
+        if let Err(e) = task.await {
+            error!("Snapshot task failed: {}", e);
+        }
+    }
+    if let Some(path) = &config.state_file {
+        if let Err(e) = persistence::save_snapshot(path, &rooms).await {
+            error!("Failed to save snapshot to {}: {}", path.display(), e);
+        }
+    }
+    info!("Server stopped");
+}
+
+/// Reports a problem that keeps the server from starting and exits.
+fn startup_error(message: String) -> ! {
+    eprintln!("{}", message);
+    process::exit(1);
+}
+
+fn with_rooms(rooms: Rooms) -> impl Filter<Extract = (Rooms,), Error = std::convert::Infallible> + Clone {
+    warp::any().map(move || rooms.clone())
+}
+
+fn with_shutdown_flag(flag: ShutdownFlag) -> impl Filter<Extract = (ShutdownFlag,), Error = std::convert::Infallible> + Clone {
+    warp::any().map(move || flag.clone())
+}
+
+fn with_event_log(event_log: Option<Arc<EventLog>>) -> impl Filter<Extract = (Option<Arc<EventLog>>,), Error = std::convert::Infallible> + Clone {
+    warp::any().map(move || event_log.clone())
+}
+
+fn with_connection_limit(limit: Arc<ConnectionLimit>) -> impl Filter<Extract = (Arc<ConnectionLimit>,), Error = std::convert::Infallible> + Clone {
+    warp::any().map(move || limit.clone())
+}
+
+fn with_config(config: Arc<ServerConfig>) -> impl Filter<Extract = (Arc<ServerConfig>,), Error = std::convert::Infallible> + Clone {
+    warp::any().map(move || config.clone())
+}
+
+async fn list_rooms_handler(rooms: Rooms) -> Result<impl Reply, Rejection> {
+    Ok(warp::reply::json(&room::list_rooms(&rooms).await))
+}
+
+// One argument per route filter
+#[allow(clippy::too_many_arguments)]
+async fn ws_handler(
+    room_id: String,
+    ws: warp::ws::Ws,
+    origin: Option<String>,
+    rooms: Rooms,
+    config: Arc<ServerConfig>,
+    shutdown_flag: ShutdownFlag,
+    event_log: Option<Arc<EventLog>>,
+    connection_limit: Arc<ConnectionLimit>,
+) -> Result<warp::reply::Response, Rejection> {
+    info!("New WebSocket connection request for room {}", room_id);
+    if shutdown::is_shutting_down(&shutdown_flag) {
+        info!("Refusing WebSocket connection during shutdown");
+        return Ok(warp::reply::with_status("Server is shutting down", StatusCode::SERVICE_UNAVAILABLE).into_response());
+    }
+    if !room::is_valid_room_id(&room_id) {
+        error!("Rejecting WebSocket connection with invalid room id: {}", room_id);
+        return Ok(warp::reply::with_status("Invalid room id", StatusCode::BAD_REQUEST).into_response());
+    }
+    // Browsers let any page open a socket here, so only upgrade for pages we trust
+    if !origin::is_allowed(&config.allowed_origins, origin.as_deref()) {
+        warn!("Rejecting WebSocket connection from disallowed origin {}", origin.as_deref().unwrap_or_default());
+        return Ok(warp::reply::with_status("Origin not allowed", StatusCode::FORBIDDEN).into_response());
+    }
+    let Some(slot) = connection_limit.try_acquire() else {
+        warn!("Refusing WebSocket connection, the server is full");
+        return Ok(warp::reply::with_status("Server is full", StatusCode::SERVICE_UNAVAILABLE).into_response());
+    };
+    if !room::can_join(&rooms, &room_id, config.max_rooms).await {
+        warn!("Refusing WebSocket connection, too many rooms are open to create room {}", room_id);
+        return Ok(warp::reply::with_status("Too many rooms", StatusCode::SERVICE_UNAVAILABLE).into_response());
+    }
+
+    let ws = ws.max_message_size(config.max_message_size).max_frame_size(config.max_frame_size);
+    Ok(ws
+        .on_upgrade(move |socket| async move {
+            handle_websocket(socket, rooms, room_id, config, event_log).await;
+            drop(slot);
+        })
+        .into_response())
+}
+
+/// Tells a client its messages are being dropped for exceeding its rate limit.
+async fn warn_rate_limited(clients: &Clients, client_id: &str) {
+    warn!("Client {} exceeded its rate limit, dropping messages", client_id);
+    let reply = ServerMessage::error(ErrorCode::RateLimited, "Too many messages; slow down or you will be disconnected");
+    send_server_message(clients, client_id, &reply).await;
 }
 
-async fn send_game_state_to_client(clients: &Clients, game_state: &SharedGameState, client_id: &str) {
-    let player_info = {
-        let state_lock = game_state.read().await;
-        state_lock.get_all_player_info().clone()
+/// Closes the connection of a client that kept exceeding its rate limit.
+async fn disconnect_rate_limited(clients: &Clients, client_id: &str) {
+    warn!("Client {} kept exceeding its rate limit, disconnecting", client_id);
+    if let Err(e) = send_to_client(clients, client_id, Message::close_with(CLOSE_CODE_RATE_LIMITED, "Rate limit exceeded")).await {
+        error!("Error sending close to client {}: {}", client_id, e);
+    }
+}
+
+async fn handle_websocket(ws: warp::ws::WebSocket, rooms: Rooms, room_id: String, config: Arc<ServerConfig>, event_log: Option<Arc<EventLog>>) {
+    info!("WebSocket connection established from browser");
+
+    // Generate unique client ID
+    let client_id = Uuid::new_v4().to_string();
+    info!("Client connected with ID: {}", client_id);
+
+    // Split the websocket stream into sender and receiver
+    let (sender, mut receiver) = ws.split();
+
+    // Outbound messages are queued per client and written by a dedicated task
+    let queue = OutboundQueue::new(config.outbound_queue_capacity, config.outbound_overflow_policy);
+    let mut writer = tokio::spawn(outbound::run_writer(client_id.clone(), queue.clone(), sender));
+
+    // Add client to its room, creating the room on first use. The room cap was
+    // checked before the upgrade, but another client may have taken the last slot
+    let Some(room) = room::join_room(&rooms, &room_id, &client_id, queue.clone(), &config.map, event_log, config.max_rooms).await else {
+        warn!("Closing connection {}, too many rooms are open to create room {}", client_id, room_id);
+        if let Err(e) = queue.push(Message::close_with(CLOSE_CODE_TOO_MANY_ROOMS, "Too many rooms")) {
+            error!("Error sending close to client {}: {}", client_id, e);
+        }
+        queue.close();
+        return;
     };
+    let clients = &room.clients;
+    let game_state = &room.game_state;
+    let client_to_player = &room.client_to_player;
 
-    if !player_info.is_empty() {
-        let game_state_message = GameMessage {
-            message_type: "game_state".to_string(),
-            player_id: None,
-            player_name: None,
-            color: None,
-            position: None,
-            data: Some(serde_json::to_value(player_info).unwrap_or_default()),
+    // Send the map and current game state to the new client
+    send_game_state_to_client(&room, &config, &client_id).await;
+
+    // Broadcast new client connection to all other clients
+    broadcast_client_connected(clients, &client_id).await;
+
+    // Ping the client periodically; any frame it sends counts as a sign of life
+    let mut heartbeat = tokio::time::interval(config.heartbeat_interval);
+    heartbeat.tick().await;
+    let mut last_seen = Instant::now();
+    let mut rate_limiter = RateLimiter::new(config.rate_limit);
+
+    // Handle incoming messages until the client leaves, its writer stops or it misses the pong deadline
+    loop {
+        let result = tokio::select! {
+            result = receiver.next() => match result {
+                Some(result) => {
+                    last_seen = Instant::now();
+                    result
+                }
+                None => break,
+            },
+            _ = heartbeat.tick() => {
+                if last_seen.elapsed() > config.pong_timeout {
+                    warn!("Client {} missed the pong deadline, disconnecting", client_id);
+                    writer.abort();
+                    break;
+                }
+                if let Err(e) = send_to_client(clients, &client_id, Message::ping(Vec::new())).await {
+                    error!("Error sending ping to client {}: {}", client_id, e);
+                }
+                continue;
+            }
+            _ = &mut writer => {
+                info!("Outbound queue for client {} closed, dropping connection", client_id);
+                break;
+            }
+            _ = queue.overflowed() => {
+                warn!("Client {} is not keeping up with its outbound queue, disconnecting", client_id);
+                writer.abort();
+                break;
+            }
         };
 
-        if let Ok(msg_str) = serde_json::to_string(&game_state_message) {
-            info!("Sending game state to new client {}: {}", client_id, msg_str);
-            if let Err(e) = send_to_client(clients, client_id, Message::text(msg_str)).await {
-                error!("Error sending game state to client {}: {}", client_id, e);
+        match result {
+            Ok(msg) => {
+                if msg.is_text() {
+                    let text = msg.to_str().unwrap_or("Invalid UTF-8");
+                    debug!("Received message from client {}: {}", client_id, text);
+
+                    // Parsed first so the message also counts against its type's limit
+                    let parsed = serde_json::from_str::<ClientMessage>(text);
+                    match rate_limiter.check(parsed.as_ref().ok().and_then(MessageKind::of)) {
+                        Verdict::Allow => {}
+                        Verdict::Drop => continue,
+                        Verdict::Warn => {
+                            warn_rate_limited(clients, &client_id).await;
+                            continue;
+                        }
+                        Verdict::Disconnect => {
+                            disconnect_rate_limited(clients, &client_id).await;
+                            break;
+                        }
+                    }
+
+                    match parsed {
+                        Ok(client_msg) => {
+                            handle_game_message(&room, &config, &client_id, client_msg).await;
+                        }
+                        Err(e) => {
+                            error!("Rejecting invalid message from client {}: {}", client_id, e);
+                            let reply = ServerMessage::error(ErrorCode::InvalidMessage, e.to_string());
+                            send_server_message(clients, &client_id, &reply).await;
+                        }
+                    }
+                } else if msg.is_binary() {
+                    let data = msg.as_bytes();
+                    debug!("Received binary message with {} bytes from client {}", data.len(), client_id);
+
+                    match rate_limiter.check(Some(MessageKind::Binary)) {
+                        Verdict::Allow => {}
+                        Verdict::Drop => continue,
+                        Verdict::Warn => {
+                            warn_rate_limited(clients, &client_id).await;
+                            continue;
+                        }
+                        Verdict::Disconnect => {
+                            disconnect_rate_limited(clients, &client_id).await;
+                            break;
+                        }
+                    }
+
+                    // Broadcast binary data to all clients
+                    broadcast_binary(clients, &client_id, data).await;
+                } else if msg.is_ping() {
+                    info!("Received ping from client {}, sending pong", client_id);
+                    if let Err(e) = send_to_client(clients, &client_id, Message::pong(msg.as_bytes())).await {
+                        error!("Error sending pong to client {}: {}", client_id, e);
+                        break;
+                    }
+                } else if msg.is_pong() {
+                    info!("Received pong from client {}", client_id);
+                } else if msg.is_close() {
+                    info!("Client {} disconnected", client_id);
+                    break;
+                }
+            }
+            Err(e) => {
+                error!("WebSocket error from client {}: {}", client_id, e);
+                break;
             }
         }
     }
+
+    // Stop accepting outbound messages; the writer flushes what is queued and exits
+    queue.close();
+
+    // Set player offline if they were registered. The session lock is released
+    // first: broadcasts read sessions while holding the game state lock
+    let session = client_to_player.write().await.remove(&client_id);
+    if let Some(ClientSession { player_id, .. }) = session {
+        let mut game_state_lock = game_state.write().await;
+        game_state_lock.set_player_offline(&player_id);
+
+        // Broadcast updated game state to all remaining clients
+        let update = game_state_lock.record_snapshot();
+        info!("Broadcasting updated game state after player {} went offline", player_id);
+        publish_update(&room, &config, &game_state_lock, Some(&client_id), &update).await;
+    }
+
+    // Broadcast client disconnection to remaining clients
+    broadcast_client_disconnected(clients, &client_id).await;
+
+    // Remove client from its room, tearing the room down if it is now empty
+    room::leave_room(&rooms, &room, &client_id).await;
+
+    info!("WebSocket connection closed for client {}", client_id);
 }
 
-async fn broadcast_client_connected(clients: &Clients, client_id: &str) {
-    let connection_message = GameMessage {
-        message_type: "client_connected".to_string(),
-        player_id: Some(client_id.to_string()),
-        player_name: None,
-        color: None,
-        position: None,
-        data: None,
+async fn handle_game_message(room: &Room, config: &ServerConfig, sender_id: &str, client_msg: ClientMessage) {
+    let clients = &room.clients;
+    let game_state = &room.game_state;
+    let client_to_player = &room.client_to_player;
+
+    match client_msg {
+        ClientMessage::PlayerMove { player_id, position } => {
+            // Moves act on behalf of the player bound to this connection; the
+            // player_id in the message only matters when moving someone else
+            let Some(session) = client_to_player.read().await.get(sender_id).cloned() else {
+                error!("Rejecting player_move from client {} that has not joined", sender_id);
+                let reply = ServerMessage::error(ErrorCode::NotJoined, "Join the game before moving");
+                send_server_message(clients, sender_id, &reply).await;
+                return;
+            };
+            let player_id = player_id.unwrap_or_else(|| session.player_id.clone());
+            info!("Processing player_move from {} for {}: ({}, {})", session.player_id, player_id, position.x, position.y);
+
+            let movement_locked = game_state.read().await.is_movement_locked();
+            if movement_locked && !session.permissions.ignore_movement_lock {
+                error!("Rejecting player_move from {} while movement is locked", session.player_id);
+                let reply = ServerMessage::error(ErrorCode::MovementLocked, "Movement is locked by the game master");
+                send_server_message(clients, sender_id, &reply).await;
+                return;
+            }
+
+            if player_id != session.player_id && !session.permissions.move_any_token {
+                error!("Rejecting player_move from {} for player {} it does not own", session.player_id, player_id);
+                let reply = ServerMessage::error(ErrorCode::PermissionDenied, format!("You cannot move token '{}'", player_id));
+                send_server_message(clients, sender_id, &reply).await;
+                return;
+            }
+
+            // Plan and apply the move under one lock so no other token can
+            // step into the path in between
+            let mut state_lock = game_state.write().await;
+            let Some(current_position) = state_lock.get_player_position(&player_id).copied() else {
+                drop(state_lock);
+                error!("Rejecting player_move for unknown token {}", player_id);
+                let reply = ServerMessage::error(ErrorCode::UnknownPlayer, format!("No token with id '{}'", player_id));
+                send_server_message(clients, sender_id, &reply).await;
+                return;
+            };
+
+            // During an encounter that restricts movement, only the active token moves
+            let out_of_turn = state_lock
+                .encounter()
+                .is_some_and(|encounter| encounter.restrict_movement && !encounter.is_turn_of(&player_id));
+            if out_of_turn && !session.permissions.ignore_turn_order {
+                drop(state_lock);
+                error!("Rejecting player_move for player {} out of turn", player_id);
+                let reply = ServerMessage::error(ErrorCode::NotYourTurn, "Only the active token can move during this encounter");
+                send_server_message(clients, sender_id, &reply).await;
+                return;
+            }
+
+            // Reject illegal moves and resend the authoritative position
+            // Turns only exist during an encounter, so the budget only applies then
+            let charge_budget = !session.permissions.ignore_movement_budget && state_lock.encounter().is_some();
+            let path = match plan_move(&state_lock, config, &player_id, current_position, position, charge_budget) {
+                Ok(path) => path,
+                Err(reply) => {
+                    drop(state_lock);
+                    error!("Rejecting illegal move for player {} to ({}, {})", player_id, position.x, position.y);
+                    send_server_message(clients, sender_id, &reply).await;
+
+                    let correction = ServerMessage::PlayerMove {
+                        player_id,
+                        player_name: None,
+                        color: None,
+                        position: current_position,
+                        path: None,
+                        cost: None,
+                    };
+                    send_server_message(clients, sender_id, &correction).await;
+                    return;
+                }
+            };
+
+            // Update game state with new position and broadcast it to everyone,
+            // including the mover so it stays in step with the state version
+            let used_before = state_lock.movement_used(&player_id);
+            state_lock.update_player_position(player_id.clone(), position);
+            if charge_budget {
+                state_lock.spend_movement(&player_id, &path);
+            }
+            let used_after = state_lock.movement_used(&player_id);
+            let turn = state_lock.current_turn();
+            state_lock.record_board_change(BoardChange::TokenMoved {
+                token_id: player_id.clone(),
+                from: current_position,
+                to: position,
+                used_before,
+                used_after,
+                turn,
+            });
+            let update = state_lock.record(ServerMessage::PlayerMove {
+                player_id,
+                player_name: None,
+                color: None,
+                position,
+                path: Some(path.cells),
+                cost: Some(path.cost),
+            });
+            publish_update(room, config, &state_lock, None, &update).await;
+        }
+        ClientMessage::PlayerJoin { player_id, player_name, color, gm_secret } => {
+            info!("Player {} joining the game with name '{}' and color '{}'", player_id, player_name, color);
+            if !require_unbound(clients, client_to_player, sender_id).await {
+                return;
+            }
+
+            // Claiming the game master role requires the configured secret
+            let role = match gm_secret {
+                None => Role::Player,
+                Some(secret) if config.gm_secret.as_deref() == Some(secret.as_str()) => Role::GameMaster,
+                Some(_) => {
+                    error!("Rejecting game master claim from player {}", player_id);
+                    let reply = ServerMessage::error(ErrorCode::PermissionDenied, "Invalid game master secret");
+                    send_server_message(clients, sender_id, &reply).await;
+                    return;
+                }
+            };
+
+            // Names and ids belong to whoever registered them first; returning
+            // players must present their reconnect token via player_reconnect
+            let mut state_lock = game_state.write().await;
+            let size = TokenSize::default();
+            let spawn = pathfinding::nearest_free_cell(state_lock.map(), state_lock.map().spawn, size, &state_lock.occupancy(None, config.pass_through).cells);
+            let join_result = {
+                if state_lock.has_token(&player_id) {
+                    Err(ServerMessage::error(
+                        ErrorCode::PlayerIdTaken,
+                        format!("Player id '{}' is already registered; use player_reconnect with your token", player_id),
+                    ))
+                } else if state_lock.find_player_by_name(&player_name).is_some() {
+                    Err(ServerMessage::error(
+                        ErrorCode::NameTaken,
+                        format!("The name '{}' is already taken; use player_reconnect with your token or pick another name", player_name),
+                    ))
+                } else if let Some(spawn) = spawn {
+                    state_lock.add_player_info(player_id.clone(), player_name.clone(), color.clone(), size, spawn);
+                    if role == Role::GameMaster {
+                        state_lock.grant_game_master(&player_id);
+                    }
+                    Ok((state_lock.issue_reconnect_token(&player_id), spawn))
+                } else {
+                    Err(ServerMessage::error(ErrorCode::Occupied, "There is no free cell near the spawn for your token"))
+                }
+            };
+
+            let (reconnect_token, spawn) = match join_result {
+                Ok(joined) => joined,
+                Err(rejection) => {
+                    error!("Rejecting player_join for {} with name '{}'", player_id, player_name);
+                    drop(state_lock);
+                    send_server_message(clients, sender_id, &rejection).await;
+                    return;
+                }
+            };
+
+            // Track the player_id for this client
+            {
+                let mut client_to_player_lock = client_to_player.write().await;
+                client_to_player_lock.insert(sender_id.to_string(), ClientSession::new(player_id.clone(), role));
+            }
+
+            let ack = ServerMessage::JoinAck {
+                player_id: player_id.clone(),
+                reconnect_token,
+                role,
+            };
+            send_server_message(clients, sender_id, &ack).await;
+
+            // Broadcast player join to all other clients, then bring the new
+            // player up to date with a snapshot that already includes the join
+            let update = state_lock.record(ServerMessage::PlayerJoin {
+                player_id,
+                player_name: player_name.clone(),
+                color,
+                size,
+                position: Some(spawn),
+            });
+            publish_update(room, config, &state_lock, Some(sender_id), &update).await;
+            send_update(room, config, &state_lock, sender_id, &state_lock.snapshot()).await;
+            send_chat_history(room, &state_lock, sender_id).await;
+
+            let line = state_lock.system_line(format!("{} joined the game", player_name));
+            deliver_chat(room, &line).await;
+        }
+        ClientMessage::PlayerReconnect { reconnect_token } => {
+            if !require_unbound(clients, client_to_player, sender_id).await {
+                return;
+            }
+            let mut state_lock = game_state.write().await;
+            let player = {
+                let player_id = state_lock.find_player_by_reconnect_token(&reconnect_token).cloned();
+                if let Some(player_id) = &player_id {
+                    state_lock.set_player_online(player_id);
+                }
+                player_id.and_then(|player_id| {
+                    let role = state_lock.player_role(&player_id);
+                    state_lock
+                        .get_all_player_info()
+                        .get(&player_id)
+                        .map(|player_info| (player_id, player_info.name.clone(), player_info.color.clone(), role))
+                })
+            };
+
+            let Some((player_id, player_name, color, role)) = player else {
+                drop(state_lock);
+                error!("Rejecting player_reconnect from client {} with unknown token", sender_id);
+                let reply = ServerMessage::error(ErrorCode::InvalidReconnectToken, "Unknown reconnect token");
+                send_server_message(clients, sender_id, &reply).await;
+                return;
+            };
+
+            // Bind the player to this connection, releasing any stale connection
+            // still holding it so its disconnect doesn't mark the player offline
+            {
+                let mut client_to_player_lock = client_to_player.write().await;
+                client_to_player_lock.retain(|_, session| session.player_id != player_id);
+                client_to_player_lock.insert(sender_id.to_string(), ClientSession::new(player_id.clone(), role));
+            }
+
+            info!("Player '{}' reconnected as {}", player_name, player_id);
+
+            let ack = ServerMessage::JoinAck {
+                player_id: player_id.clone(),
+                reconnect_token,
+                role,
+            };
+            send_server_message(clients, sender_id, &ack).await;
+
+            // Broadcast player reconnection to all other clients and bring the
+            // returning player up to date
+            let update = state_lock.record(ServerMessage::PlayerReconnect {
+                player_id,
+                player_name: player_name.clone(),
+                color,
+            });
+            publish_update(room, config, &state_lock, Some(sender_id), &update).await;
+            send_update(room, config, &state_lock, sender_id, &state_lock.snapshot()).await;
+            send_chat_history(room, &state_lock, sender_id).await;
+
+            let line = state_lock.system_line(format!("{} reconnected", player_name));
+            deliver_chat(room, &line).await;
+        }
+        ClientMessage::KickPlayer { player_id } => {
+            if require_game_master(clients, client_to_player, sender_id).await.is_none() {
+                return;
+            }
+
+            let mut state_lock = game_state.write().await;
+            let Some(player_name) = state_lock.get_all_player_info().get(&player_id).map(|player_info| player_info.name.clone()) else {
+                drop(state_lock);
+                let reply = ServerMessage::error(ErrorCode::UnknownPlayer, format!("No player with id '{}'", player_id));
+                send_server_message(clients, sender_id, &reply).await;
+                return;
+            };
+
+            let turn = state_lock.remove_player(&player_id);
+
+            // Unbind the kicked player's connections so they can no longer act as them
+            let kicked_clients: Vec<ClientId> = {
+                let mut client_to_player_lock = client_to_player.write().await;
+                let kicked_clients = client_to_player_lock
+                    .iter()
+                    .filter(|(_, session)| session.player_id == player_id)
+                    .map(|(client_id, _)| client_id.clone())
+                    .collect::<Vec<_>>();
+                for client_id in &kicked_clients {
+                    client_to_player_lock.remove(client_id);
+                }
+                kicked_clients
+            };
+
+            info!("Game master kicked player {} ({} connections)", player_id, kicked_clients.len());
+            let update = state_lock.record(ServerMessage::PlayerKicked { player_id });
+            publish_update(room, config, &state_lock, None, &update).await;
+            if let Some(turn) = turn {
+                for update in state_lock.record_turn_change(turn) {
+                    publish_update(room, config, &state_lock, None, &update).await;
+                }
+            }
+            let line = state_lock.system_line(format!("{} was kicked by the game master", player_name));
+            drop(state_lock);
+            deliver_chat(room, &line).await;
+
+            for client_id in kicked_clients {
+                if let Err(e) = send_to_client(clients, &client_id, Message::close_with(CLOSE_CODE_KICKED, "Kicked by the game master")).await {
+                    error!("Error closing kicked client {}: {}", client_id, e);
+                }
+            }
+        }
+        ClientMessage::RemovePlayer { player_id } => {
+            if require_game_master(clients, client_to_player, sender_id).await.is_none() {
+                return;
+            }
+
+            let mut state_lock = game_state.write().await;
+            let result = {
+                match state_lock.get_all_player_info().get(&player_id) {
+                    None => Err(ServerMessage::error(ErrorCode::UnknownPlayer, format!("No player with id '{}'", player_id))),
+                    Some(player_info) if player_info.online => Err(ServerMessage::error(
+                        ErrorCode::PlayerOnline,
+                        format!("Player '{}' is online; kick them instead", player_id),
+                    )),
+                    Some(player_info) => {
+                        let player_name = player_info.name.clone();
+                        Ok((player_name, state_lock.remove_player(&player_id)))
+                    }
+                }
+            };
+
+            match result {
+                Ok((player_name, turn)) => {
+                    info!("Game master removed offline player {}", player_id);
+                    let update = state_lock.record(ServerMessage::PlayerRemoved { player_id });
+                    publish_update(room, config, &state_lock, None, &update).await;
+                    if let Some(turn) = turn {
+                        for update in state_lock.record_turn_change(turn) {
+                            publish_update(room, config, &state_lock, None, &update).await;
+                        }
+                    }
+                    let line = state_lock.system_line(format!("{} was removed from the game", player_name));
+                    deliver_chat(room, &line).await;
+                }
+                Err(reply) => {
+                    drop(state_lock);
+                    send_server_message(clients, sender_id, &reply).await;
+                }
+            }
+        }
+        ClientMessage::LockMovement { locked } => {
+            if require_game_master(clients, client_to_player, sender_id).await.is_none() {
+                return;
+            }
+
+            let mut state_lock = game_state.write().await;
+            state_lock.set_movement_locked(locked);
+            let update = state_lock.record(ServerMessage::MovementLocked { locked });
+            publish_update(room, config, &state_lock, None, &update).await;
+        }
+        ClientMessage::ResetBoard => {
+            if require_game_master(clients, client_to_player, sender_id).await.is_none() {
+                return;
+            }
+
+            let mut state_lock = game_state.write().await;
+            state_lock.reset_board();
+            let update = state_lock.record_snapshot();
+            publish_update(room, config, &state_lock, None, &update).await;
+        }
+        ClientMessage::StartEncounter { restrict_movement } => {
+            if require_game_master(clients, client_to_player, sender_id).await.is_none() {
+                return;
+            }
+
+            let mut state_lock = game_state.write().await;
+            if state_lock.encounter().is_some() {
+                drop(state_lock);
+                let reply = ServerMessage::error(ErrorCode::EncounterActive, "End the current encounter first");
+                send_server_message(clients, sender_id, &reply).await;
+                return;
+            }
+
+            state_lock.start_encounter(restrict_movement);
+            let update = state_lock.record(ServerMessage::EncounterStarted { restrict_movement });
+            publish_update(room, config, &state_lock, None, &update).await;
+        }
+        ClientMessage::SubmitInitiative { player_id, initiative } => {
+            let Some(session) = client_to_player.read().await.get(sender_id).cloned() else {
+                let reply = ServerMessage::error(ErrorCode::NotJoined, "Join the game before rolling initiative");
+                send_server_message(clients, sender_id, &reply).await;
+                return;
+            };
+            let player_id = player_id.unwrap_or_else(|| session.player_id.clone());
+            if player_id != session.player_id && !session.is_game_master() {
+                let reply = ServerMessage::error(ErrorCode::PermissionDenied, format!("You cannot roll initiative for player '{}'", player_id));
+                send_server_message(clients, sender_id, &reply).await;
+                return;
+            }
+
+            let mut state_lock = game_state.write().await;
+            let result = if !state_lock.has_token(&player_id) {
+                Err(ServerMessage::error(ErrorCode::UnknownPlayer, format!("No token with id '{}'", player_id)))
+            } else {
+                state_lock
+                    .submit_initiative(&player_id, initiative)
+                    .ok_or_else(|| ServerMessage::error(ErrorCode::NoEncounter, "There is no encounter running"))
+            };
+
+            match result {
+                Ok(order) => {
+                    let update = state_lock.record(ServerMessage::InitiativeUpdated { order });
+                    publish_update(room, config, &state_lock, None, &update).await;
+                }
+                Err(reply) => {
+                    drop(state_lock);
+                    send_server_message(clients, sender_id, &reply).await;
+                }
+            }
+        }
+        ClientMessage::NextTurn => {
+            if require_game_master(clients, client_to_player, sender_id).await.is_none() {
+                return;
+            }
+
+            let mut state_lock = game_state.write().await;
+            let result = if state_lock.encounter().is_none() {
+                Err(ServerMessage::error(ErrorCode::NoEncounter, "There is no encounter running"))
+            } else {
+                state_lock
+                    .next_turn()
+                    .ok_or_else(|| ServerMessage::error(ErrorCode::InitiativeEmpty, "Nobody has rolled initiative yet"))
+            };
+
+            match result {
+                Ok(turn) => {
+                    for update in state_lock.record_turn_change(turn) {
+                        publish_update(room, config, &state_lock, None, &update).await;
+                    }
+                }
+                Err(reply) => {
+                    drop(state_lock);
+                    send_server_message(clients, sender_id, &reply).await;
+                }
+            }
+        }
+        ClientMessage::EndEncounter => {
+            if require_game_master(clients, client_to_player, sender_id).await.is_none() {
+                return;
+            }
+
+            let mut state_lock = game_state.write().await;
+            if state_lock.encounter().is_none() {
+                drop(state_lock);
+                let reply = ServerMessage::error(ErrorCode::NoEncounter, "There is no encounter running");
+                send_server_message(clients, sender_id, &reply).await;
+                return;
+            }
+
+            state_lock.end_encounter();
+            let update = state_lock.record(ServerMessage::EncounterEnded);
+            publish_update(room, config, &state_lock, None, &update).await;
+        }
+        ClientMessage::ResetMovement => {
+            if require_game_master(clients, client_to_player, sender_id).await.is_none() {
+                return;
+            }
+
+            let mut state_lock = game_state.write().await;
+            state_lock.reset_movement();
+            let update = state_lock.record(ServerMessage::MovementReset);
+            publish_update(room, config, &state_lock, None, &update).await;
+        }
+        ClientMessage::SetDoor { position, open } => {
+            if require_game_master(clients, client_to_player, sender_id).await.is_none() {
+                return;
+            }
+
+            let mut state_lock = game_state.write().await;
+            let was_open = state_lock.map().is_door_open(&position);
+            if !state_lock.set_door(&position, open) {
+                drop(state_lock);
+                let reply = ServerMessage::error(ErrorCode::NoDoor, format!("No door at ({}, {})", position.x, position.y));
+                send_server_message(clients, sender_id, &reply).await;
+                return;
+            }
+            if was_open != Some(open) {
+                state_lock.record_board_change(BoardChange::DoorSet { position, open });
+            }
+
+            let update = state_lock.record_map_state();
+            publish_update(room, config, &state_lock, None, &update).await;
+
+            // Doors change what players can see, so refresh everyone's view
+            if config.fog_of_war {
+                let update = state_lock.record_snapshot();
+                publish_update(room, config, &state_lock, None, &update).await;
+            }
+        }
+        ClientMessage::CreateNpc { name, color, icon, size, faction, position, hidden, hp } => {
+            if require_game_master(clients, client_to_player, sender_id).await.is_none() {
+                return;
+            }
+
+            if let Some(Err(reason)) = hp.map(|hp| hp.validate()) {
+                let reply = ServerMessage::error(ErrorCode::InvalidStatus, format!("Invalid hit points: {}", reason));
+                send_server_message(clients, sender_id, &reply).await;
+                return;
+            }
+
+            let mut state_lock = game_state.write().await;
+            let occupancy = state_lock.occupancy(None, config.pass_through);
+            if let Err(reply) = check_destination(&state_lock, &occupancy.cells, position, size) {
+                drop(state_lock);
+                send_server_message(clients, sender_id, &reply).await;
+                return;
+            }
+
+            let token = NpcToken {
+                name,
+                color,
+                icon,
+                size,
+                faction,
+                position,
+                hidden,
+                status: TokenStatus {
+                    hp,
+                    conditions: Vec::new(),
+                },
+            };
+            let token_id = format!("npc-{}", Uuid::new_v4().simple());
+            state_lock.add_npc(token_id.clone(), token.clone());
+            state_lock.record_board_change(BoardChange::NpcCreated {
+                token_id: token_id.clone(),
+                token: Box::new(token.clone()),
+                initiative: None,
+            });
+            let update = state_lock.record(ServerMessage::NpcCreated {
+                token_id,
+                token: Box::new(token),
+            });
+            publish_update(room, config, &state_lock, None, &update).await;
+        }
+        ClientMessage::UpdateNpc { token_id, changes } => {
+            if require_game_master(clients, client_to_player, sender_id).await.is_none() {
+                return;
+            }
+
+            let mut state_lock = game_state.write().await;
+
+            // A token that grows must still fit where it stands
+            let resized = changes
+                .size
+                .zip(state_lock.get_all_npcs().get(&token_id).map(|token| token.position));
+            if let Some((size, position)) = resized {
+                let occupancy = state_lock.occupancy(Some(&token_id), config.pass_through);
+                if let Err(reply) = check_destination(&state_lock, &occupancy.cells, position, size) {
+                    drop(state_lock);
+                    send_server_message(clients, sender_id, &reply).await;
+                    return;
+                }
+            }
+
+            let before = state_lock.get_all_npcs().get(&token_id).cloned();
+            let (Some(before), Some(token)) = (before, state_lock.update_npc(&token_id, changes)) else {
+                drop(state_lock);
+                let reply = ServerMessage::error(ErrorCode::UnknownToken, format!("No token with id '{}'", token_id));
+                send_server_message(clients, sender_id, &reply).await;
+                return;
+            };
+            let concealed = token.hidden && !before.hidden;
+            state_lock.record_board_change(BoardChange::NpcUpdated {
+                token_id: token_id.clone(),
+                before: Box::new(before),
+                after: Box::new(token.clone()),
+            });
+
+            let update = state_lock.record(ServerMessage::NpcUpdated {
+                token_id,
+                token: Box::new(token),
+                concealed,
+            });
+            publish_update(room, config, &state_lock, None, &update).await;
+        }
+        ClientMessage::RemoveNpc { token_id } => {
+            if require_game_master(clients, client_to_player, sender_id).await.is_none() {
+                return;
+            }
+
+            let mut state_lock = game_state.write().await;
+            let Some(token) = state_lock.get_all_npcs().get(&token_id).cloned() else {
+                drop(state_lock);
+                let reply = ServerMessage::error(ErrorCode::UnknownToken, format!("No token with id '{}'", token_id));
+                send_server_message(clients, sender_id, &reply).await;
+                return;
+            };
+
+            let hidden = token.hidden;
+            let initiative = state_lock.encounter().and_then(|encounter| encounter.slot_of(&token_id));
+            let turn = state_lock.remove_npc(&token_id);
+            state_lock.record_board_change(BoardChange::NpcRemoved {
+                token_id: token_id.clone(),
+                token: Box::new(token),
+                initiative,
+            });
+            let update = state_lock.record(ServerMessage::NpcRemoved { token_id, hidden });
+            publish_update(room, config, &state_lock, None, &update).await;
+            if let Some(turn) = turn {
+                for update in state_lock.record_turn_change(turn) {
+                    publish_update(room, config, &state_lock, None, &update).await;
+                }
+            }
+        }
+        ClientMessage::Undo | ClientMessage::Redo => {
+            if require_game_master(clients, client_to_player, sender_id).await.is_none() {
+                return;
+            }
+
+            let undo = matches!(client_msg, ClientMessage::Undo);
+            let mut state_lock = game_state.write().await;
+            let change = if undo {
+                state_lock.board_history.pop_undo()
+            } else {
+                state_lock.board_history.pop_redo()
+            };
+            let Some(change) = change else {
+                drop(state_lock);
+                let reply = if undo {
+                    ServerMessage::error(ErrorCode::NothingToUndo, "There is nothing to undo")
+                } else {
+                    ServerMessage::error(ErrorCode::NothingToRedo, "There is nothing to redo")
+                };
+                send_server_message(clients, sender_id, &reply).await;
+                return;
+            };
+
+            // Undoing applies the change backwards; if the board no longer
+            // allows it, the change stays where it was
+            let applied = if undo { change.clone().inverse() } else { change.clone() };
+            let turn = match apply_board_change(&mut state_lock, &applied) {
+                Ok(turn) => turn,
+                Err(reply) => {
+                    if undo {
+                        state_lock.board_history.push_undo(change);
+                    } else {
+                        state_lock.board_history.push_redo(change);
+                    }
+                    drop(state_lock);
+                    send_server_message(clients, sender_id, &reply).await;
+                    return;
+                }
+            };
+            if undo {
+                state_lock.board_history.push_redo(change);
+            } else {
+                state_lock.board_history.push_undo(change);
+            }
+            info!("Game master {} the last board change", if undo { "undid" } else { "redid" });
+
+            if let BoardChange::DoorSet { .. } = applied {
+                let update = state_lock.record_map_state();
+                publish_update(room, config, &state_lock, None, &update).await;
+            }
+            let update = state_lock.record_snapshot();
+            publish_update(room, config, &state_lock, None, &update).await;
+            if let Some(turn) = turn {
+                for update in state_lock.record_turn_change(turn) {
+                    publish_update(room, config, &state_lock, None, &update).await;
+                }
+            }
+        }
+        ClientMessage::UpdateToken { token_id, hp, add_conditions, remove_conditions, size } => {
+            let Some(session) = client_to_player.read().await.get(sender_id).cloned() else {
+                let reply = ServerMessage::error(ErrorCode::NotJoined, "Join the game before editing tokens");
+                send_server_message(clients, sender_id, &reply).await;
+                return;
+            };
+            let token_id = token_id.unwrap_or_else(|| session.player_id.clone());
+            if token_id != session.player_id && !session.is_game_master() {
+                let reply = ServerMessage::error(ErrorCode::PermissionDenied, format!("You cannot edit token '{}'", token_id));
+                send_server_message(clients, sender_id, &reply).await;
+                return;
+            }
+            if size.is_some() && !session.is_game_master() {
+                let reply = ServerMessage::error(ErrorCode::PermissionDenied, "Only the game master can resize tokens");
+                send_server_message(clients, sender_id, &reply).await;
+                return;
+            }
+
+            let invalid = match hp.map(|hp| hp.validate()) {
+                Some(Err(reason)) => Some(format!("Invalid hit points: {}", reason)),
+                _ if add_conditions.iter().any(|active| active.rounds == Some(0)) => {
+                    Some("Condition durations must be at least one round".to_string())
+                }
+                _ => None,
+            };
+            if let Some(reason) = invalid {
+                let reply = ServerMessage::error(ErrorCode::InvalidStatus, reason);
+                send_server_message(clients, sender_id, &reply).await;
+                return;
+            }
+
+            let mut state_lock = game_state.write().await;
+            let Some(before) = state_lock.token_status(&token_id).cloned() else {
+                drop(state_lock);
+                let reply = ServerMessage::error(ErrorCode::UnknownToken, format!("No token with id '{}'", token_id));
+                send_server_message(clients, sender_id, &reply).await;
+                return;
+            };
+
+            // A token that grows must still fit where it stands
+            let resized = size.zip(state_lock.get_player_position(&token_id).copied());
+            if let Some((size, position)) = resized {
+                let occupancy = state_lock.occupancy(Some(&token_id), config.pass_through);
+                if let Err(reply) = check_destination(&state_lock, &occupancy.cells, position, size) {
+                    drop(state_lock);
+                    send_server_message(clients, sender_id, &reply).await;
+                    return;
+                }
+            }
+
+            if hp.is_some() || !add_conditions.is_empty() || !remove_conditions.is_empty() {
+                state_lock.update_status(&token_id, hp, add_conditions, remove_conditions);
+                let after = state_lock.token_status(&token_id).cloned().unwrap_or_default();
+                state_lock.record_board_change(BoardChange::StatusUpdated {
+                    token_id: token_id.clone(),
+                    before,
+                    after,
+                });
+                info!("Player {} updated the status of token {}", session.player_id, token_id);
+            }
+            if let Some(size) = size {
+                state_lock.set_token_size(&token_id, size);
+            }
+
+            if let Some(message) = state_lock.token_updated_message(&token_id) {
+                let update = state_lock.record(message);
+                publish_update(room, config, &state_lock, None, &update).await;
+            }
+        }
+        ClientMessage::Roll { notation, secret, label } => {
+            let Some(session) = client_to_player.read().await.get(sender_id).cloned() else {
+                let reply = ServerMessage::error(ErrorCode::NotJoined, "Join the game before rolling");
+                send_server_message(clients, sender_id, &reply).await;
+                return;
+            };
+
+            let expression = match notation.parse::<DiceExpression>() {
+                Ok(expression) => expression,
+                Err(e) => {
+                    error!("Rejecting roll '{}' from {}: {}", notation, session.player_id, e);
+                    let reply = ServerMessage::error(ErrorCode::InvalidDice, format!("Invalid roll '{}': {}", notation, e));
+                    send_server_message(clients, sender_id, &reply).await;
+                    return;
+                }
+            };
+
+            let (roll, line) = {
+                let mut state_lock = game_state.write().await;
+                let player_name = state_lock
+                    .get_all_player_info()
+                    .get(&session.player_id)
+                    .map(|player_info| player_info.name.clone())
+                    .unwrap_or_default();
+                let roll = make_roll(&expression, &session.player_id, player_name, label, secret);
+                state_lock.log_roll(roll.clone());
+
+                // Public rolls are also announced in chat
+                let line = (!secret).then(|| {
+                    let label = roll.label.as_ref().map(|label| format!(" for {}", label)).unwrap_or_default();
+                    state_lock.system_line(format!("{} rolled {}{}: {}", roll.player_name, roll.notation, label, roll.total))
+                });
+                (roll, line)
+            };
+            info!("Player {} rolled {} = {}{}", roll.player_id, roll.notation, roll.total, if secret { " (secret)" } else { "" });
+
+            let message = ServerMessage::DiceRolled(Box::new(roll));
+            if secret {
+                // Secret rolls only go to the roller and the game master
+                let recipients: Vec<ClientId> = client_to_player
+                    .read()
+                    .await
+                    .iter()
+                    .filter(|(client_id, session)| client_id.as_str() == sender_id || session.is_game_master())
+                    .map(|(client_id, _)| client_id.clone())
+                    .collect();
+                for client_id in recipients {
+                    send_server_message(clients, &client_id, &message).await;
+                }
+            } else {
+                broadcast_server_message_to_all(clients, &message).await;
+            }
+            if let Some(line) = line {
+                deliver_chat(room, &line).await;
+            }
+        }
+        ClientMessage::Chat { text, channel, to } => {
+            let Some(session) = client_to_player.read().await.get(sender_id).cloned() else {
+                let reply = ServerMessage::error(ErrorCode::NotJoined, "Join the game before chatting");
+                send_server_message(clients, sender_id, &reply).await;
+                return;
+            };
+
+            let text = match chat::sanitize(&text) {
+                Ok(text) => text,
+                Err(reason) => {
+                    let reply = ServerMessage::error(ErrorCode::InvalidChat, reason);
+                    send_server_message(clients, sender_id, &reply).await;
+                    return;
+                }
+            };
+
+            let mut state_lock = game_state.write().await;
+            let recipient = match (channel, to) {
+                (ChatChannel::System, _) => Err(ServerMessage::error(ErrorCode::PermissionDenied, "Only the server can post system messages")),
+                (ChatChannel::Whisper, None) => Err(ServerMessage::error(ErrorCode::InvalidChat, "Whispers need a recipient in 'to'")),
+                (ChatChannel::Whisper, Some(name)) => match state_lock.find_player_by_name(&name) {
+                    Some(player_id) => Ok(Some((player_id.clone(), name))),
+                    None => Err(ServerMessage::error(ErrorCode::UnknownPlayer, format!("No player named '{}'", name))),
+                },
+                (ChatChannel::Public | ChatChannel::Gm, _) => Ok(None),
+            };
+
+            let (to_player_id, to_name) = match recipient {
+                Ok(recipient) => recipient.unzip(),
+                Err(reply) => {
+                    drop(state_lock);
+                    send_server_message(clients, sender_id, &reply).await;
+                    return;
+                }
+            };
+
+            let from_name = state_lock
+                .get_all_player_info()
+                .get(&session.player_id)
+                .map(|player_info| player_info.name.clone());
+            let message = ChatMessage {
+                id: Uuid::new_v4().simple().to_string(),
+                channel,
+                from_player_id: Some(session.player_id.clone()),
+                from_name,
+                to_player_id,
+                to_name,
+                text,
+                sent_at: unix_time(),
+            };
+            state_lock.log_chat(message.clone());
+            drop(state_lock);
+
+            deliver_chat(room, &message).await;
+        }
+        ClientMessage::GetRollLog => {
+            let Some(session) = client_to_player.read().await.get(sender_id).cloned() else {
+                let reply = ServerMessage::error(ErrorCode::NotJoined, "Join the game before reading the roll log");
+                send_server_message(clients, sender_id, &reply).await;
+                return;
+            };
+
+            let rolls = game_state.read().await.roll_log_for(&session.player_id, session.is_game_master());
+            send_server_message(clients, sender_id, &ServerMessage::RollLog { rolls }).await;
+        }
+        ClientMessage::GetPositions => {
+            // Send current positions to the requesting client, limited to what it can see
+            let response = {
+                let state_lock = game_state.read().await;
+                let response = ServerMessage::PositionsUpdate {
+                    data: state_lock.get_all_positions().clone(),
+                };
+                if config.fog_of_war || state_lock.has_hidden_tokens() {
+                    let viewer = Viewer::for_session(client_to_player.read().await.get(sender_id), &state_lock, config.fog_of_war);
+                    visibility::filter_message(&state_lock, &viewer, &response, config.sight_radius)
+                } else {
+                    response
+                }
+            };
+            send_server_message(clients, sender_id, &response).await;
+        }
+        ClientMessage::Resync { since_version } => {
+            // Hold the lock so no new update can interleave with the replay
+            let state_lock = game_state.read().await;
+            match state_lock.updates_since(since_version) {
+                Some(updates) => {
+                    info!("Resyncing client {} from version {} with {} updates", sender_id, since_version, updates.len());
+                    for update in &updates {
+                        send_update(room, config, &state_lock, sender_id, update).await;
+                    }
+                }
+                None => {
+                    info!("Client {} is too far behind at version {}, sending snapshot", sender_id, since_version);
+                    send_server_message(clients, sender_id, &state_lock.stamp(state_lock.map_state_message())).await;
+                    send_update(room, config, &state_lock, sender_id, &state_lock.snapshot()).await;
+                }
+            }
+
+            let complete = ServerMessage::ResyncComplete {
+                version: state_lock.version(),
+            };
+            send_server_message(clients, sender_id, &complete).await;
+        }
+    }
+}
+
+/// Returns the sender's session if it belongs to a game master, replying with
+/// `permission_denied` otherwise.
+async fn require_game_master(clients: &Clients, client_to_player: &ClientToPlayerMap, sender_id: &str) -> Option<ClientSession> {
+    let session = client_to_player.read().await.get(sender_id).cloned();
+    match session {
+        Some(session) if session.is_game_master() => Some(session),
+        _ => {
+            error!("Rejecting game master command from client {}", sender_id);
+            let reply = ServerMessage::error(ErrorCode::PermissionDenied, "Only the game master can do that");
+            send_server_message(clients, sender_id, &reply).await;
+            None
+        }
+    }
+}
+
+/// Checks that a connection is not bound to a player yet, so joining cannot
+/// leave the player it already has online with no connection.
+async fn require_unbound(clients: &Clients, client_to_player: &ClientToPlayerMap, sender_id: &str) -> bool {
+    let session = client_to_player.read().await.get(sender_id).map(|session| session.player_id.clone());
+    let Some(player_id) = session else {
+        return true;
     };
+    error!("Rejecting join from client {} already bound to player {}", sender_id, player_id);
+    let reply = ServerMessage::error(ErrorCode::AlreadyJoined, format!("This connection already plays as '{}'", player_id));
+    send_server_message(clients, sender_id, &reply).await;
+    false
+}
 
-    if let Ok(msg_str) = serde_json::to_string(&connection_message) {
-        info!("Broadcasting client connected: {}", msg_str);
-        broadcast_message(clients, client_id, &msg_str).await;
+/// Rolls `expression` with the server's RNG on behalf of `player_id`.
+fn make_roll(expression: &DiceExpression, player_id: &str, player_name: String, label: Option<String>, secret: bool) -> DiceRoll {
+    let (terms, total) = expression.roll(&mut rand::thread_rng());
+
+    DiceRoll {
+        id: Uuid::new_v4().simple().to_string(),
+        player_id: player_id.to_string(),
+        player_name,
+        notation: expression.to_string(),
+        label: label.map(|label| label.chars().take(MAX_ROLL_LABEL_LEN).collect()),
+        secret,
+        terms,
+        total,
+        rolled_at: unix_time(),
     }
 }
 
-async fn broadcast_client_disconnected(clients: &Clients, client_id: &str) {
-    let disconnection_message = GameMessage {
-        message_type: "client_disconnected".to_string(),
-        player_id: Some(client_id.to_string()),
-        player_name: None,
-        color: None,
-        position: None,
-        data: None,
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or_default()
+}
+
+/// Checks that `player_id` may move from `from` to `to` and finds the path it
+/// takes, or returns the error to send back.
+fn plan_move(state: &GameState, config: &ServerConfig, player_id: &str, from: Position, to: Position, charge_budget: bool) -> Result<Path, ServerMessage> {
+    let map = state.map();
+    let size = state.token_size(player_id);
+    let occupancy = state.occupancy(Some(player_id), config.pass_through);
+    check_destination(state, &occupancy.cells, to, size)?;
+
+    let used = state.movement_used(player_id);
+    let budget = config.movement_budget.filter(|_| charge_budget);
+    let remaining = budget.map(|budget| budget.saturating_sub(used.squares));
+    pathfinding::find_path(map, from, to, size, &occupancy.blocking, config.diagonal_rule, used.odd_diagonal, remaining).map_err(|e| match e {
+        PathError::Unreachable => ServerMessage::error(ErrorCode::NoPath, format!("There is no path to ({}, {})", to.x, to.y)),
+        PathError::OverBudget => ServerMessage::error(
+            ErrorCode::MovementExceeded,
+            format!(
+                "Moving there costs more than the {} of {} squares that remain this turn",
+                remaining.unwrap_or_default(),
+                budget.unwrap_or_default()
+            ),
+        ),
+    })
+}
+
+/// Applies a board change for undo or redo, provided the map still allows it.
+/// Tokens are put back even if another token has since moved there, as they
+/// may have shared a cell before. Returns the new turn if a token whose turn
+/// it was is taken off the board.
+fn apply_board_change(state: &mut GameState, change: &BoardChange) -> Result<Option<TurnStart>, ServerMessage> {
+    let unknown_token = |token_id: &str| ServerMessage::error(ErrorCode::UnknownToken, format!("No token with id '{}'", token_id));
+    let unoccupied = HashSet::new();
+    match change {
+        BoardChange::TokenMoved { token_id, to, used_after, turn, .. } => {
+            if !state.has_token(token_id) {
+                return Err(unknown_token(token_id));
+            }
+            check_destination(state, &unoccupied, *to, state.token_size(token_id))?;
+            state.update_player_position(token_id.clone(), *to);
+            // A later turn has its own budget, which the move never touched
+            if state.current_turn() == *turn {
+                state.set_movement_used(token_id, *used_after);
+            }
+        }
+        BoardChange::DoorSet { position, open } => {
+            if !state.set_door(position, *open) {
+                return Err(ServerMessage::error(ErrorCode::NoDoor, format!("No door at ({}, {})", position.x, position.y)));
+            }
+        }
+        BoardChange::NpcCreated { token_id, token, initiative } => {
+            check_destination(state, &unoccupied, token.position, token.size)?;
+            state.add_npc(token_id.clone(), (**token).clone());
+            if let Some(slot) = initiative {
+                return Ok(state.restore_initiative(slot.clone()));
+            }
+        }
+        BoardChange::NpcUpdated { token_id, after, .. } => {
+            let Some(current) = state.get_all_npcs().get(token_id) else {
+                return Err(unknown_token(token_id));
+            };
+            // Only the details change; where the token stands and its status
+            // are undone separately
+            let mut token = (**after).clone();
+            token.position = current.position;
+            token.status = current.status.clone();
+            if token.size != current.size {
+                check_destination(state, &unoccupied, token.position, token.size)?;
+            }
+            state.add_npc(token_id.clone(), token);
+        }
+        BoardChange::NpcRemoved { token_id, .. } => {
+            if !state.get_all_npcs().contains_key(token_id) {
+                return Err(unknown_token(token_id));
+            }
+            return Ok(state.remove_npc(token_id));
+        }
+        BoardChange::StatusUpdated { token_id, after, .. } => {
+            if !state.set_status(token_id, after.clone()) {
+                return Err(unknown_token(token_id));
+            }
+        }
+    }
+    Ok(None)
+}
+
+/// Checks that a token of `size` could stand at `to`: every cell it would
+/// cover is on the map, passable and not `occupied`.
+fn check_destination(state: &GameState, occupied: &HashSet<Position>, to: Position, size: TokenSize) -> Result<(), ServerMessage> {
+    let map = state.map();
+    for cell in size.footprint(to) {
+        if !map.contains(&cell) {
+            return Err(ServerMessage::error(ErrorCode::OutOfBounds, format!("Position ({}, {}) is outside the map", cell.x, cell.y)));
+        }
+        if !map.is_passable(&cell) {
+            return Err(ServerMessage::error(ErrorCode::Blocked, format!("Position ({}, {}) is blocked", cell.x, cell.y)));
+        }
+        if occupied.contains(&cell) {
+            return Err(ServerMessage::error(ErrorCode::Occupied, format!("Position ({}, {}) is occupied by another token", cell.x, cell.y)));
+        }
+    }
+    Ok(())
+}
+
+async fn broadcast_server_message(clients: &Clients, sender_id: &str, message: &impl Serialize) {
+    match serde_json::to_string(message) {
+        Ok(msg_str) => broadcast_message(clients, Some(sender_id), &msg_str).await,
+        Err(e) => error!("Failed to serialize server message for broadcast: {}", e),
+    }
+}
+
+async fn broadcast_server_message_to_all(clients: &Clients, message: &impl Serialize) {
+    match serde_json::to_string(message) {
+        Ok(msg_str) => broadcast_message(clients, None, &msg_str).await,
+        Err(e) => error!("Failed to serialize server message for broadcast: {}", e),
+    }
+}
+
+/// Broadcasts a state update to every client in the room except `exclude`.
+///
+/// Under fog of war, or when the update could reveal a hidden token, each client
+/// gets its own filtered copy, so callers pass the locked game state the update was recorded against.
+async fn publish_update(room: &Room, config: &ServerConfig, state: &GameState, exclude: Option<&str>, update: &StateUpdate) {
+    if !visibility::needs_filtering(state, config.fog_of_war, &update.message) {
+        match serde_json::to_string(update) {
+            Ok(msg_str) => broadcast_message(&room.clients, exclude, &msg_str).await,
+            Err(e) => error!("Failed to serialize state update for broadcast: {}", e),
+        }
+        return;
+    }
+
+    let recipients: Vec<(ClientId, Viewer)> = {
+        let client_to_player_lock = room.client_to_player.read().await;
+        let clients_lock = room.clients.read().await;
+        clients_lock
+            .keys()
+            .filter(|client_id| Some(client_id.as_str()) != exclude)
+            .map(|client_id| (client_id.clone(), Viewer::for_session(client_to_player_lock.get(client_id), state, config.fog_of_war)))
+            .collect()
     };
 
-    if let Ok(msg_str) = serde_json::to_string(&disconnection_message) {
-        info!("Broadcasting client disconnected: {}", msg_str);
-        broadcast_message(clients, client_id, &msg_str).await;
+    for (client_id, viewer) in recipients {
+        for filtered in visibility::filter_update(state, &viewer, update, config.sight_radius) {
+            send_server_message(&room.clients, &client_id, &filtered).await;
+        }
     }
 }
+
+/// Sends a chat message to every client in the room allowed to read it.
+async fn deliver_chat(room: &Room, message: &ChatMessage) {
+    let recipients: Vec<ClientId> = {
+        let client_to_player_lock = room.client_to_player.read().await;
+        let clients_lock = room.clients.read().await;
+        clients_lock
+            .keys()
+            .filter(|client_id| message.is_visible_to(client_to_player_lock.get(*client_id)))
+            .cloned()
+            .collect()
+    };
+
+    let chat_message = ServerMessage::ChatMessage(Box::new(message.clone()));
+    for client_id in recipients {
+        send_server_message(&room.clients, &client_id, &chat_message).await;
+    }
+}
+
+/// Sends a newly joined client the chat history it is allowed to read.
+async fn send_chat_history(room: &Room, state: &GameState, client_id: &str) {
+    let messages = state.chat_history_for(room.client_to_player.read().await.get(client_id));
+    send_server_message(&room.clients, client_id, &ServerMessage::ChatHistory { messages }).await;
+}
+
+/// Sends a state update to one client, filtered by what it can see.
+async fn send_update(room: &Room, config: &ServerConfig, state: &GameState, client_id: &str, update: &StateUpdate) {
+    if !visibility::needs_filtering(state, config.fog_of_war, &update.message) {
+        send_server_message(&room.clients, client_id, update).await;
+        return;
+    }
+
+    let viewer = Viewer::for_session(room.client_to_player.read().await.get(client_id), state, config.fog_of_war);
+    for filtered in visibility::filter_update(state, &viewer, update, config.sight_radius) {
+        send_server_message(&room.clients, client_id, &filtered).await;
+    }
+}
+
+/// Queues `message` for one client. Only its type is logged: some messages,
+/// like `join_ack`, carry secrets.
+async fn send_server_message(clients: &Clients, client_id: &str, message: &impl Serialize) {
+    match serde_json::to_string(message) {
+        Ok(msg_str) => {
+            debug!("Sending {} message to client {}", message_type(&msg_str), client_id);
+            if let Err(e) = send_to_client(clients, client_id, Message::text(msg_str)).await {
+                error!("Error sending message to client {}: {}", client_id, e);
+            }
+        }
+        Err(e) => error!("Failed to serialize server message for client {}: {}", client_id, e),
+    }
+}
+
+/// The `type` of a serialized server message, for logging.
+fn message_type(message: &str) -> String {
+    #[derive(Deserialize)]
+    struct Typed {
+        #[serde(rename = "type")]
+        kind: String,
+    }
+    serde_json::from_str::<Typed>(message).map_or_else(|_| "untyped".to_string(), |typed| typed.kind)
+}
+
+/// Queues `message` for every client in the room except `exclude`, usually the sender.
+async fn broadcast_message(clients: &Clients, exclude: Option<&str>, message: &str) {
+    let clients_lock = clients.read().await;
+    let mut broadcast_count = 0;
+
+    debug!("Broadcasting message to {} clients (excluding {:?})", clients_lock.len(), exclude);
+
+    for (client_id, queue) in clients_lock.iter() {
+        if Some(client_id.as_str()) != exclude {
+            match queue.push(Message::text(message)) {
+                Ok(()) => broadcast_count += 1,
+                Err(e) => error!("Error broadcasting message to client {}: {}", client_id, e),
+            }
+        }
+    }
+
+    debug!("Broadcasted message to {} clients", broadcast_count);
+}
+
+async fn broadcast_binary(clients: &Clients, sender_id: &str, data: &[u8]) {
+    let clients_lock = clients.read().await;
+
+    for (client_id, queue) in clients_lock.iter() {
+        if client_id != sender_id {
+            // Don't send back to the sender
+            if let Err(e) = queue.push(Message::binary(data)) {
+                error!("Error broadcasting binary to client {}: {}", client_id, e);
+            }
+        }
+    }
+}
+
+async fn send_to_client(clients: &Clients, client_id: &str, message: Message) -> Result<(), QueueError> {
+    let clients_lock = clients.read().await;
+    match clients_lock.get(client_id) {
+        Some(queue) => queue.push(message),
+        None => Ok(()),
+    }
+}
+
+async fn send_game_state_to_client(room: &Room, config: &ServerConfig, client_id: &str) {
+    let state_lock = room.game_state.read().await;
+    let snapshot = state_lock.snapshot();
+    info!("Sending game state version {} to new client {}", snapshot.version, client_id);
+    send_server_message(&room.clients, client_id, &state_lock.stamp(state_lock.map_state_message())).await;
+    send_update(room, config, &state_lock, client_id, &snapshot).await;
+}
+
+async fn broadcast_client_connected(clients: &Clients, client_id: &str) {
+    let connection_message = ServerMessage::ClientConnected {
+        player_id: client_id.to_string(),
+    };
+    info!("Broadcasting client connected: {}", client_id);
+    broadcast_server_message(clients, client_id, &connection_message).await;
+}
+
+async fn broadcast_client_disconnected(clients: &Clients, client_id: &str) {
+    let disconnection_message = ServerMessage::ClientDisconnected {
+        player_id: client_id.to_string(),
+    };
+    info!("Broadcasting client disconnected: {}", client_id);
+    broadcast_server_message(clients, client_id, &disconnection_message).await;
+}
@@ -0,0 +1,141 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::Position;
+
+/// Grid dimensions of the Dungeon Scrawl map the frontend renders.
+const DEFAULT_MAP_WIDTH: i32 = 40;
+const DEFAULT_MAP_HEIGHT: i32 = 25;
+
+/// Where players are placed when they join or the board is reset, unless the map says otherwise.
+const DEFAULT_SPAWN: Position = Position { x: 0, y: 0 };
+
+/// Server-side description of the playable grid.
+///
+/// Map files are JSON in this same shape; everything but the size is optional.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MapDefinition {
+    pub width: i32,
+    pub height: i32,
+    #[serde(default = "default_spawn")]
+    pub spawn: Position,
+    /// Cells that block both movement and line of sight.
+    #[serde(default)]
+    pub walls: HashSet<Position>,
+    /// Cells that block movement but not line of sight, such as pits or statues.
+    #[serde(default)]
+    pub blocked: HashSet<Position>,
+    /// Closed doors behave like walls, open ones like floor.
+    #[serde(default)]
+    pub doors: Vec<Door>,
+    /// Cells that cost extra movement to enter.
+    #[serde(default)]
+    pub difficult: HashSet<Position>,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct Door {
+    pub position: Position,
+    #[serde(default)]
+    pub open: bool,
+}
+
+fn default_spawn() -> Position {
+    DEFAULT_SPAWN
+}
+
+impl MapDefinition {
+    pub fn new(width: i32, height: i32) -> Self {
+        Self {
+            width,
+            height,
+            spawn: DEFAULT_SPAWN,
+            walls: HashSet::new(),
+            blocked: HashSet::new(),
+            doors: Vec::new(),
+            difficult: HashSet::new(),
+        }
+    }
+
+    /// Loads and validates a map file.
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let contents = fs::read_to_string(path)?;
+        let map: MapDefinition =
+            serde_json::from_str(&contents).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        map.validate().map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        Ok(map)
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        if self.width <= 0 || self.height <= 0 {
+            return Err(format!("map size {}x{} must be positive", self.width, self.height));
+        }
+
+        let cells = self
+            .walls
+            .iter()
+            .chain(&self.blocked)
+            .chain(&self.difficult)
+            .chain(self.doors.iter().map(|door| &door.position));
+        for cell in cells {
+            if !self.contains(cell) {
+                return Err(format!("cell ({}, {}) is outside the {}x{} map", cell.x, cell.y, self.width, self.height));
+            }
+        }
+
+        if !self.is_passable(&self.spawn) {
+            return Err(format!("spawn ({}, {}) is not a passable cell", self.spawn.x, self.spawn.y));
+        }
+        Ok(())
+    }
+
+    /// Returns true if `position` lies on the grid.
+    pub fn contains(&self, position: &Position) -> bool {
+        position.x >= 0 && position.y >= 0 && position.x < self.width && position.y < self.height
+    }
+
+    /// Returns true if a token may stand on `position`.
+    pub fn is_passable(&self, position: &Position) -> bool {
+        self.contains(position)
+            && !self.walls.contains(position)
+            && !self.blocked.contains(position)
+            && !self.is_closed_door(position)
+    }
+
+    pub fn blocks_sight(&self, position: &Position) -> bool {
+        self.walls.contains(position) || self.is_closed_door(position)
+    }
+
+    pub fn is_difficult(&self, position: &Position) -> bool {
+        self.difficult.contains(position)
+    }
+
+    /// Whether the door at `position` is open, or `None` if there is no door there.
+    pub fn is_door_open(&self, position: &Position) -> Option<bool> {
+        self.doors.iter().find(|door| door.position == *position).map(|door| door.open)
+    }
+
+    fn is_closed_door(&self, position: &Position) -> bool {
+        self.doors.iter().any(|door| door.position == *position && !door.open)
+    }
+
+    /// Opens or closes the door at `position`, returning false if there is none.
+    pub fn set_door(&mut self, position: &Position, open: bool) -> bool {
+        match self.doors.iter_mut().find(|door| door.position == *position) {
+            Some(door) => {
+                door.open = open;
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+impl Default for MapDefinition {
+    fn default() -> Self {
+        Self::new(DEFAULT_MAP_WIDTH, DEFAULT_MAP_HEIGHT)
+    }
+}
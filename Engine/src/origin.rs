@@ -0,0 +1,93 @@
+/// Allowlist entry that lets any origin connect.
+pub const ANY_ORIGIN: &str = "*";
+
+/// Whether a WebSocket upgrade may proceed given its `Origin` header.
+///
+/// Clients that send no `Origin` are not browsers and are let through. Any
+/// page needs its origin in `allowed`, unless `allowed` contains `*`. The
+/// `Host` header is deliberately not trusted: a DNS rebinding page controls it.
+pub fn is_allowed(allowed: &[String], origin: Option<&str>) -> bool {
+    let Some(origin) = origin else {
+        return true;
+    };
+    let origin = origin.to_ascii_lowercase();
+    allowed.iter().any(|entry| entry == ANY_ORIGIN || *entry == origin)
+}
+
+/// Checks an allowlist entry: `*`, `null` for pages opened from files, or an origin as browsers
+/// send it, a scheme and host with an optional port and no path.
+pub fn is_valid(entry: &str) -> bool {
+    if entry == ANY_ORIGIN || entry == "null" {
+        return true;
+    }
+    let Some((scheme, host)) = entry.split_once("://") else {
+        return false;
+    };
+    !scheme.is_empty()
+        && scheme.chars().all(|c| c.is_ascii_alphanumeric() || c == '+' || c == '-' || c == '.')
+        && !host.is_empty()
+        && !host.contains('/')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn allowlist(entries: &[&str]) -> Vec<String> {
+        entries.iter().map(|entry| entry.to_string()).collect()
+    }
+
+    #[test]
+    fn clients_without_an_origin_are_allowed() {
+        assert!(is_allowed(&[], None));
+    }
+
+    #[test]
+    fn listed_origins_are_allowed() {
+        let allowed = allowlist(&["http://localhost:3000", "null"]);
+        assert!(is_allowed(&allowed, Some("http://localhost:3000")));
+        assert!(is_allowed(&allowed, Some("null")));
+    }
+
+    #[test]
+    fn origins_are_compared_case_insensitively() {
+        let allowed = allowlist(&["https://table.example.com"]);
+        assert!(is_allowed(&allowed, Some("HTTPS://Table.Example.com")));
+    }
+
+    #[test]
+    fn unlisted_origins_are_rejected() {
+        let allowed = allowlist(&["http://localhost:3000"]);
+        assert!(!is_allowed(&allowed, Some("http://localhost:3001")));
+        assert!(!is_allowed(&allowed, Some("https://localhost:3000")));
+        assert!(!is_allowed(&allowed, Some("http://evil.example")));
+        assert!(!is_allowed(&[], Some("null")));
+    }
+
+    #[test]
+    fn the_servers_own_host_is_not_trusted() {
+        assert!(!is_allowed(&[], Some("http://127.0.0.1:8000")));
+        assert!(!is_allowed(&[], Some("http://localhost:8000")));
+    }
+
+    #[test]
+    fn a_wildcard_allows_every_origin() {
+        let allowed = allowlist(&[ANY_ORIGIN]);
+        assert!(is_allowed(&allowed, Some("http://evil.example")));
+        assert!(is_allowed(&allowed, Some("null")));
+    }
+
+    #[test]
+    fn valid_entries() {
+        for entry in ["*", "null", "http://localhost", "http://localhost:3000", "https://table.example.com", "app+x-y.z://host"] {
+            assert!(is_valid(entry), "{entry}");
+        }
+    }
+
+    #[test]
+    fn invalid_entries() {
+        for entry in ["", "localhost:3000", "://host", "http://", "http://host/", "http://host/path", "ht tp://host"] {
+            assert!(!is_valid(entry), "{entry}");
+        }
+    }
+}
@@ -0,0 +1,163 @@
+use futures::SinkExt;
+use log::{error, info, warn};
+use std::collections::VecDeque;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use tokio::sync::Notify;
+use warp::ws::Message;
+
+use crate::ClientSender;
+
+/// What to do when a client's outbound queue is full.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    /// Discard the oldest queued message to make room for the new one.
+    DropOldest,
+    /// Close the connection; the client can reconnect and resync.
+    Disconnect,
+}
+
+impl FromStr for OverflowPolicy {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "drop_oldest" => Ok(OverflowPolicy::DropOldest),
+            "disconnect" => Ok(OverflowPolicy::Disconnect),
+            other => Err(format!("unknown overflow policy '{}', expected 'drop_oldest' or 'disconnect'", other)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QueueError {
+    /// The connection is closing and no longer accepts messages.
+    Closed,
+    /// The queue was full and the client was disconnected.
+    Overflow,
+}
+
+impl fmt::Display for QueueError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            QueueError::Closed => write!(f, "client queue is closed"),
+            QueueError::Overflow => write!(f, "client queue overflowed"),
+        }
+    }
+}
+
+impl std::error::Error for QueueError {}
+
+struct QueueState {
+    messages: VecDeque<Message>,
+    closed: bool,
+}
+
+/// Bounded queue of messages waiting to be written to one client.
+///
+/// Senders only ever enqueue, so a slow client never blocks the rest of the room;
+/// the connection's writer task drains the queue into the socket.
+pub struct OutboundQueue {
+    state: Mutex<QueueState>,
+    notify: Notify,
+    overflowed: Notify,
+    capacity: usize,
+    policy: OverflowPolicy,
+}
+
+impl OutboundQueue {
+    pub fn new(capacity: usize, policy: OverflowPolicy) -> Arc<Self> {
+        Arc::new(Self {
+            state: Mutex::new(QueueState {
+                messages: VecDeque::with_capacity(capacity),
+                closed: false,
+            }),
+            notify: Notify::new(),
+            overflowed: Notify::new(),
+            capacity,
+            policy,
+        })
+    }
+
+    pub fn push(&self, message: Message) -> Result<(), QueueError> {
+        let mut state = self.state.lock().unwrap();
+        if state.closed {
+            return Err(QueueError::Closed);
+        }
+
+        if state.messages.len() >= self.capacity {
+            match self.policy {
+                OverflowPolicy::DropOldest => {
+                    state.messages.pop_front();
+                    warn!("Client queue full, dropped oldest message");
+                }
+                OverflowPolicy::Disconnect => {
+                    state.messages.clear();
+                    state.closed = true;
+                    drop(state);
+                    self.notify.notify_one();
+                    self.overflowed.notify_one();
+                    return Err(QueueError::Overflow);
+                }
+            }
+        }
+
+        state.messages.push_back(message);
+        drop(state);
+        self.notify.notify_one();
+        Ok(())
+    }
+
+    /// Stops accepting messages; anything already queued is still written.
+    pub fn close(&self) {
+        self.state.lock().unwrap().closed = true;
+        self.notify.notify_one();
+    }
+
+    /// Completes once the queue has overflowed under `OverflowPolicy::Disconnect`.
+    ///
+    /// The writer may be stuck on a socket the client stopped reading, so the
+    /// connection task must drop the socket rather than wait for the writer.
+    pub async fn overflowed(&self) {
+        self.overflowed.notified().await
+    }
+
+    /// Waits for the next message, returning `None` once the queue is closed and drained.
+    async fn pop(&self) -> Option<Message> {
+        loop {
+            let notified = self.notify.notified();
+            {
+                let mut state = self.state.lock().unwrap();
+                if let Some(message) = state.messages.pop_front() {
+                    return Some(message);
+                }
+                if state.closed {
+                    return None;
+                }
+            }
+            notified.await;
+        }
+    }
+}
+
+/// Drains `queue` into the socket until the queue closes, a close frame is sent
+/// or the socket fails.
+pub async fn run_writer(client_id: String, queue: Arc<OutboundQueue>, mut sink: ClientSender) {
+    while let Some(message) = queue.pop().await {
+        let is_close = message.is_close();
+        if let Err(e) = sink.send(message).await {
+            error!("Error writing to client {}: {}", client_id, e);
+            break;
+        }
+        if is_close {
+            break;
+        }
+    }
+
+    queue.close();
+    if let Err(e) = sink.close().await {
+        info!("Error closing socket for client {}: {}", client_id, e);
+    }
+    info!("Writer for client {} stopped", client_id);
+}
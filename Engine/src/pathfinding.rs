@@ -0,0 +1,326 @@
+use serde::{Deserialize, Serialize};
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap, HashSet, VecDeque};
+use std::str::FromStr;
+
+use crate::map::MapDefinition;
+use crate::token::TokenSize;
+use crate::Position;
+
+const NEIGHBOURS: [(i32, i32); 8] = [(1, 0), (-1, 0), (0, 1), (0, -1), (1, 1), (1, -1), (-1, 1), (-1, -1)];
+
+/// How diagonal steps are charged against a movement budget.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DiagonalRule {
+    /// Every diagonal step costs one square (D&D 5e).
+    Uniform,
+    /// Diagonal steps alternate between one and two squares (5-10-5).
+    Alternating,
+}
+
+impl FromStr for DiagonalRule {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "5e" => Ok(DiagonalRule::Uniform),
+            "5-10-5" => Ok(DiagonalRule::Alternating),
+            other => Err(format!("unknown diagonal rule '{}', expected '5e' or '5-10-5'", other)),
+        }
+    }
+}
+
+/// Which other tokens a moving token may walk through. It can never end its
+/// move on top of another token either way.
+#[derive(Debug, Clone, Copy)]
+pub struct PassThrough {
+    pub allies: bool,
+    pub enemies: bool,
+}
+
+/// Cells covered by tokens other than the one moving.
+#[derive(Debug, Clone, Default)]
+pub struct Occupancy {
+    /// Every covered cell; a move may not end on any of them.
+    pub cells: HashSet<Position>,
+    /// The covered cells the moving token may not pass through.
+    pub blocking: HashSet<Position>,
+}
+
+/// Movement a token has spent this turn.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub struct MovementUsed {
+    pub squares: u32,
+    /// Under the 5-10-5 rule, whether the next diagonal step costs two squares.
+    pub odd_diagonal: bool,
+}
+
+impl MovementUsed {
+    pub fn spend(&mut self, path: &Path) {
+        self.squares += path.cost;
+        self.odd_diagonal = path.odd_diagonal;
+    }
+}
+
+/// A route between two cells, excluding the start and including the destination.
+#[derive(Debug, Clone)]
+pub struct Path {
+    pub cells: Vec<Position>,
+    pub cost: u32,
+    /// Diagonal parity after walking the path, see `MovementUsed::odd_diagonal`.
+    pub odd_diagonal: bool,
+}
+
+/// Why `find_path` found no path.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PathError {
+    /// No path leads to the destination.
+    Unreachable,
+    /// No path within `max_cost` leads to the destination.
+    OverBudget,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+struct Node {
+    position: Position,
+    odd_diagonal: bool,
+}
+
+/// Finds the cheapest path for a token of `size` from `from` to `to` with A*.
+///
+/// No cell of the token's footprint may enter a wall, blocked cell, closed
+/// door or `blocking` cell, diagonal steps may not cut the corner of a wall,
+/// and a step costs double if any cell it enters is difficult terrain.
+/// Paths costing more than `max_cost` are not explored, which keeps the search
+/// small when the destination is out of reach.
+// The map and token first, then the movement rules
+#[allow(clippy::too_many_arguments)]
+pub fn find_path(
+    map: &MapDefinition,
+    from: Position,
+    to: Position,
+    size: TokenSize,
+    blocking: &HashSet<Position>,
+    rule: DiagonalRule,
+    odd_diagonal: bool,
+    max_cost: Option<u32>,
+) -> Result<Path, PathError> {
+    let fits = |position: Position| size.footprint(position).all(|cell| map.is_passable(&cell));
+    let start = Node {
+        position: from,
+        odd_diagonal,
+    };
+    let mut open = BinaryHeap::new();
+    let mut best_cost: HashMap<Node, u32> = HashMap::new();
+    let mut came_from: HashMap<Node, Node> = HashMap::new();
+    let mut pruned = false;
+
+    best_cost.insert(start, 0);
+    open.push(Reverse((distance(from, to), 0, from.x, from.y, odd_diagonal)));
+
+    while let Some(Reverse((_, cost, x, y, odd_diagonal))) = open.pop() {
+        let node = Node {
+            position: Position { x, y },
+            odd_diagonal,
+        };
+        if node.position == to {
+            return Ok(reconstruct(&came_from, node, cost));
+        }
+        if best_cost.get(&node).is_some_and(|best| cost > *best) {
+            continue;
+        }
+
+        for (dx, dy) in NEIGHBOURS {
+            let next = Position { x: x + dx, y: y + dy };
+            if !fits(next) || size.footprint(next).any(|cell| blocking.contains(&cell)) {
+                continue;
+            }
+
+            let diagonal = dx != 0 && dy != 0;
+            if diagonal && (!fits(Position { x: x + dx, y }) || !fits(Position { x, y: y + dy })) {
+                continue;
+            }
+
+            let (mut step_cost, next_odd_diagonal) = match (diagonal, rule) {
+                (false, _) | (true, DiagonalRule::Uniform) => (1, odd_diagonal),
+                (true, DiagonalRule::Alternating) => (if odd_diagonal { 2 } else { 1 }, !odd_diagonal),
+            };
+            if size.footprint(next).any(|cell| map.is_difficult(&cell)) {
+                step_cost *= 2;
+            }
+
+            let next_node = Node {
+                position: next,
+                odd_diagonal: next_odd_diagonal,
+            };
+            let next_cost = cost + step_cost;
+            if max_cost.is_some_and(|max_cost| next_cost > max_cost) {
+                pruned = true;
+                continue;
+            }
+            if best_cost.get(&next_node).is_some_and(|best| next_cost >= *best) {
+                continue;
+            }
+            best_cost.insert(next_node, next_cost);
+            came_from.insert(next_node, node);
+            open.push(Reverse((next_cost + distance(next, to), next_cost, next.x, next.y, next_odd_diagonal)));
+        }
+    }
+
+    Err(if pruned { PathError::OverBudget } else { PathError::Unreachable })
+}
+
+/// Finds the cell nearest to `from` where a token of `size` fits without
+/// covering an `occupied` cell, searching outwards through passable cells so
+/// the token is never put somewhere cut off from `from`. Returns `None` if
+/// no such cell exists.
+pub fn nearest_free_cell(map: &MapDefinition, from: Position, size: TokenSize, occupied: &HashSet<Position>) -> Option<Position> {
+    let mut queue = VecDeque::from([from]);
+    let mut seen = HashSet::from([from]);
+    while let Some(position) = queue.pop_front() {
+        if size.footprint(position).all(|cell| map.is_passable(&cell) && !occupied.contains(&cell)) {
+            return Some(position);
+        }
+        for (dx, dy) in NEIGHBOURS {
+            let next = Position {
+                x: position.x + dx,
+                y: position.y + dy,
+            };
+            if map.is_passable(&next) && seen.insert(next) {
+                queue.push_back(next);
+            }
+        }
+    }
+    None
+}
+
+/// Chebyshev distance: the fewest steps between two cells, and never more than the real cost.
+fn distance(a: Position, b: Position) -> u32 {
+    (a.x - b.x).unsigned_abs().max((a.y - b.y).unsigned_abs())
+}
+
+fn reconstruct(came_from: &HashMap<Node, Node>, goal: Node, cost: u32) -> Path {
+    let mut cells = Vec::new();
+    let mut node = goal;
+    while let Some(previous) = came_from.get(&node) {
+        cells.push(node.position);
+        node = *previous;
+    }
+    cells.reverse();
+
+    Path {
+        cells,
+        cost,
+        odd_diagonal: goal.odd_diagonal,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(x: i32, y: i32) -> Position {
+        Position { x, y }
+    }
+
+    fn path(map: &MapDefinition, from: Position, to: Position, rule: DiagonalRule) -> Result<Path, PathError> {
+        find_path(map, from, to, TokenSize::Medium, &HashSet::new(), rule, false, None)
+    }
+
+    #[test]
+    fn walks_a_straight_line() {
+        let map = MapDefinition::new(10, 10);
+        let path = path(&map, at(0, 0), at(4, 0), DiagonalRule::Uniform).unwrap();
+        assert_eq!(path.cells, vec![at(1, 0), at(2, 0), at(3, 0), at(4, 0)]);
+        assert_eq!(path.cost, 4);
+    }
+
+    #[test]
+    fn charges_diagonals_one_square_under_5e() {
+        let map = MapDefinition::new(10, 10);
+        let path = path(&map, at(0, 0), at(3, 3), DiagonalRule::Uniform).unwrap();
+        assert_eq!(path.cells, vec![at(1, 1), at(2, 2), at(3, 3)]);
+        assert_eq!(path.cost, 3);
+    }
+
+    #[test]
+    fn alternates_diagonal_costs_under_5_10_5() {
+        let map = MapDefinition::new(10, 10);
+        let path = path(&map, at(0, 0), at(3, 3), DiagonalRule::Alternating).unwrap();
+        assert_eq!(path.cost, 4);
+        assert!(path.odd_diagonal);
+
+        // Parity carries over from earlier moves in the turn
+        let path = find_path(&map, at(0, 0), at(1, 1), TokenSize::Medium, &HashSet::new(), DiagonalRule::Alternating, true, None).unwrap();
+        assert_eq!(path.cost, 2);
+        assert!(!path.odd_diagonal);
+    }
+
+    #[test]
+    fn does_not_cut_wall_corners() {
+        let mut map = MapDefinition::new(10, 10);
+        map.walls.insert(at(1, 0));
+        let path = path(&map, at(0, 0), at(1, 1), DiagonalRule::Uniform).unwrap();
+        assert_eq!(path.cells, vec![at(0, 1), at(1, 1)]);
+        assert_eq!(path.cost, 2);
+    }
+
+    #[test]
+    fn doubles_the_cost_of_difficult_terrain() {
+        let mut map = MapDefinition::new(3, 1);
+        map.difficult.insert(at(1, 0));
+        let path = path(&map, at(0, 0), at(2, 0), DiagonalRule::Uniform).unwrap();
+        assert_eq!(path.cost, 3);
+    }
+
+    #[test]
+    fn routes_around_blocking_cells() {
+        let map = MapDefinition::new(10, 10);
+        let blocking = HashSet::from([at(1, 0), at(1, 1)]);
+        let path = find_path(&map, at(0, 0), at(2, 0), TokenSize::Medium, &blocking, DiagonalRule::Uniform, false, None).unwrap();
+        assert!(path.cells.iter().all(|cell| !blocking.contains(cell)));
+        assert_eq!(path.cost, 4);
+    }
+
+    #[test]
+    fn reports_unreachable_cells() {
+        let mut map = MapDefinition::new(10, 10);
+        map.walls.extend([at(4, 5), at(6, 5), at(5, 4), at(5, 6), at(4, 4), at(6, 4), at(4, 6), at(6, 6)]);
+        assert_eq!(path(&map, at(0, 0), at(5, 5), DiagonalRule::Uniform).unwrap_err(), PathError::Unreachable);
+    }
+
+    #[test]
+    fn stops_searching_at_the_budget() {
+        let map = MapDefinition::new(10, 10);
+        let search = |max_cost| find_path(&map, at(0, 0), at(6, 0), TokenSize::Medium, &HashSet::new(), DiagonalRule::Uniform, false, Some(max_cost));
+        assert_eq!(search(6).unwrap().cost, 6);
+        assert_eq!(search(5).unwrap_err(), PathError::OverBudget);
+    }
+
+    #[test]
+    fn finds_the_nearest_free_cell() {
+        let mut map = MapDefinition::new(5, 5);
+        assert_eq!(nearest_free_cell(&map, at(0, 0), TokenSize::Medium, &HashSet::new()), Some(at(0, 0)));
+
+        let occupied = HashSet::from([at(0, 0)]);
+        let cell = nearest_free_cell(&map, at(0, 0), TokenSize::Medium, &occupied).unwrap();
+        assert_eq!(distance(cell, at(0, 0)), 1);
+
+        // A large token needs its whole footprint clear of walls and tokens
+        map.walls.insert(at(1, 1));
+        let cell = nearest_free_cell(&map, at(0, 0), TokenSize::Large, &occupied).unwrap();
+        assert!(TokenSize::Large.footprint(cell).all(|cell| map.is_passable(&cell) && !occupied.contains(&cell)));
+
+        let everywhere: HashSet<Position> = (0..5).flat_map(|x| (0..5).map(move |y| at(x, y))).collect();
+        assert_eq!(nearest_free_cell(&map, at(0, 0), TokenSize::Medium, &everywhere), None);
+    }
+
+    #[test]
+    fn keeps_large_footprints_off_walls() {
+        let mut map = MapDefinition::new(4, 4);
+        map.walls.insert(at(1, 2));
+        let path = find_path(&map, at(0, 0), at(2, 0), TokenSize::Large, &HashSet::new(), DiagonalRule::Uniform, false, None).unwrap();
+        assert_eq!(path.cost, 2);
+        assert!(find_path(&map, at(0, 0), at(0, 2), TokenSize::Large, &HashSet::new(), DiagonalRule::Uniform, false, None).is_err());
+    }
+}
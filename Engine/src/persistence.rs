@@ -0,0 +1,88 @@
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::oneshot;
+
+use crate::events::{EventLog, GameEvent, RoomLog};
+use crate::room::{Room, Rooms};
+use crate::GameState;
+
+/// On-disk form of every room's game state.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Snapshot {
+    pub rooms: HashMap<String, GameState>,
+}
+
+/// Loads a snapshot from `path`, returning an empty one if the file does not exist.
+pub fn load_snapshot(path: &Path) -> io::Result<Snapshot> {
+    let contents = match fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Snapshot::default()),
+        Err(e) => return Err(e),
+    };
+    serde_json::from_str(&contents).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+/// Builds the room registry from a snapshot. Restored players stay offline until they reconnect.
+/// Each room writes its restored state to `event_log` so the log can be replayed on its own.
+pub fn restore_rooms(snapshot: Snapshot, event_log: Option<Arc<EventLog>>) -> HashMap<String, Arc<Room>> {
+    snapshot
+        .rooms
+        .into_iter()
+        .map(|(room_id, mut game_state)| {
+            if let Some(event_log) = &event_log {
+                game_state.attach_event_log(RoomLog::new(room_id.clone(), event_log.clone()));
+                game_state.log_event(GameEvent::RoomRestored {
+                    state: Box::new(game_state.clone()),
+                });
+            }
+            game_state.set_all_players_offline();
+            let room = Arc::new(Room::with_game_state(room_id.clone(), game_state));
+            (room_id, room)
+        })
+        .collect()
+}
+
+/// Writes the current state of every room to `path`.
+///
+/// The snapshot goes to a temporary file first and is renamed into place, so a
+/// crash mid-write never leaves a truncated snapshot behind.
+pub async fn save_snapshot(path: &Path, rooms: &Rooms) -> io::Result<()> {
+    let mut snapshot = Snapshot::default();
+    {
+        let rooms_lock = rooms.read().await;
+        for (room_id, room) in rooms_lock.iter() {
+            let game_state = room.game_state.read().await.clone();
+            snapshot.rooms.insert(room_id.clone(), game_state);
+        }
+    }
+
+    let contents = serde_json::to_string_pretty(&snapshot)?;
+    let tmp_path = path.with_extension("json.tmp");
+    tokio::fs::write(&tmp_path, contents).await?;
+    tokio::fs::rename(&tmp_path, path).await?;
+    info!("Saved snapshot of {} rooms to {}", snapshot.rooms.len(), path.display());
+    Ok(())
+}
+
+/// Periodically snapshots all rooms to `path` until `stop` fires. A save in
+/// progress is never cut short, so the task can be awaited before a final save.
+pub async fn run_snapshot_task(path: PathBuf, rooms: Rooms, period: Duration, mut stop: oneshot::Receiver<()>) {
+    let mut interval = tokio::time::interval(period);
+    // The first tick completes immediately; skip it so we don't rewrite the file we just loaded
+    interval.tick().await;
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {}
+            _ = &mut stop => return,
+        }
+        if let Err(e) = save_snapshot(&path, &rooms).await {
+            error!("Failed to save snapshot to {}: {}", path.display(), e);
+        }
+    }
+}
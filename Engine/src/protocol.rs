@@ -0,0 +1,351 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+use crate::chat::{ChatChannel, ChatMessage};
+use crate::dice::DiceRoll;
+use crate::initiative::{Encounter, InitiativeEntry};
+use crate::map::MapDefinition;
+use crate::token::{ActiveCondition, Condition, Faction, HitPoints, NpcChanges, NpcToken, TokenSize};
+use crate::{ClientId, PlayerInfo, Position, Role};
+
+/// Messages accepted from browser clients, tagged by their `type` field.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ClientMessage {
+    /// Players join as medium tokens; only the game master changes their size.
+    PlayerJoin {
+        player_id: String,
+        player_name: String,
+        color: String,
+        /// Claims the game master role when it matches the configured secret.
+        #[serde(default)]
+        gm_secret: Option<String>,
+    },
+    PlayerReconnect {
+        reconnect_token: String,
+    },
+    /// `player_id` defaults to the player bound to the connection; the game
+    /// master may also name a non-player token.
+    PlayerMove {
+        #[serde(default)]
+        player_id: Option<String>,
+        position: Position,
+    },
+    GetPositions,
+    /// `player_id` defaults to the player bound to the connection; only the
+    /// game master may roll for someone else.
+    SubmitInitiative {
+        #[serde(default)]
+        player_id: Option<String>,
+        initiative: i32,
+    },
+    /// Changes a token's hit points and conditions. `token_id` defaults to the
+    /// player bound to the connection; only the game master may edit others.
+    UpdateToken {
+        #[serde(default)]
+        token_id: Option<String>,
+        #[serde(default)]
+        hp: Option<HitPoints>,
+        /// Added or, if the token already has them, given a new duration.
+        #[serde(default)]
+        add_conditions: Vec<ActiveCondition>,
+        #[serde(default)]
+        remove_conditions: Vec<Condition>,
+        /// Only the game master may resize tokens.
+        #[serde(default)]
+        size: Option<TokenSize>,
+    },
+    /// Rolls dice in standard notation on the server, e.g. `2d20kh1+5`.
+    Roll {
+        notation: String,
+        /// Only the roller and the game master see secret rolls.
+        #[serde(default)]
+        secret: bool,
+        #[serde(default)]
+        label: Option<String>,
+    },
+    GetRollLog,
+    /// `to` names the recipient of a whisper.
+    Chat {
+        text: String,
+        #[serde(default)]
+        channel: ChatChannel,
+        #[serde(default)]
+        to: Option<String>,
+    },
+    /// Asks for every state update after `since_version`, or a full snapshot if
+    /// the server no longer has them.
+    Resync {
+        since_version: u64,
+    },
+    // Game master commands
+    KickPlayer {
+        player_id: String,
+    },
+    RemovePlayer {
+        player_id: String,
+    },
+    LockMovement {
+        locked: bool,
+    },
+    ResetBoard,
+    /// Gives every token its full movement budget back.
+    ResetMovement,
+    /// With `restrict_movement`, only the active token may move until the encounter ends.
+    StartEncounter {
+        #[serde(default)]
+        restrict_movement: bool,
+    },
+    NextTurn,
+    EndEncounter,
+    SetDoor {
+        position: Position,
+        open: bool,
+    },
+    /// Places a non-player token; the server assigns its id.
+    CreateNpc {
+        name: String,
+        color: String,
+        #[serde(default)]
+        icon: Option<String>,
+        #[serde(default)]
+        size: TokenSize,
+        #[serde(default)]
+        faction: Faction,
+        position: Position,
+        #[serde(default)]
+        hidden: bool,
+        #[serde(default)]
+        hp: Option<HitPoints>,
+    },
+    /// Changes a non-player token's details; it is moved with `player_move`.
+    UpdateNpc {
+        token_id: String,
+        #[serde(flatten)]
+        changes: NpcChanges,
+    },
+    RemoveNpc {
+        token_id: String,
+    },
+    /// Reverts the last board change: a move, door, non-player token or status edit.
+    Undo,
+    /// Reapplies the last undone change, until a new change is made.
+    Redo,
+}
+
+/// Messages sent by the server, tagged by their `type` field.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ServerMessage {
+    GameState {
+        data: HashMap<String, PlayerInfo>,
+        movement_locked: bool,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        encounter: Option<Box<Encounter>>,
+        /// Non-player tokens by id.
+        #[serde(skip_serializing_if = "HashMap::is_empty")]
+        npcs: HashMap<String, NpcToken>,
+    },
+    /// The room's map, sent on connect and whenever it changes.
+    MapState {
+        map: Box<MapDefinition>,
+    },
+    /// Sent only to the joining client; the token is the player's proof of identity for `player_reconnect`.
+    JoinAck {
+        player_id: String,
+        reconnect_token: String,
+        role: Role,
+    },
+    /// `position` is omitted when the new token is out of the recipient's sight.
+    PlayerJoin {
+        player_id: String,
+        player_name: String,
+        color: String,
+        size: TokenSize,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        position: Option<Position>,
+    },
+    PlayerReconnect {
+        player_id: String,
+        player_name: String,
+        color: String,
+    },
+    PlayerMove {
+        player_id: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        player_name: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        color: Option<String>,
+        position: Position,
+        /// Cells walked through to reach `position`, for clients to animate.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        path: Option<Vec<Position>>,
+        /// Squares of movement the move cost.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        cost: Option<u32>,
+    },
+    /// The token moved out of the recipient's sight under fog of war, or the
+    /// game master hid it.
+    TokenHidden {
+        player_id: String,
+    },
+    PositionsUpdate {
+        data: HashMap<String, Position>,
+    },
+    NpcCreated {
+        token_id: String,
+        token: Box<NpcToken>,
+    },
+    /// The token's details changed; under fog of war or when it is hidden,
+    /// players get `token_hidden` or `noop` instead.
+    NpcUpdated {
+        token_id: String,
+        token: Box<NpcToken>,
+        /// Whether this change hid the token; players get `token_hidden` for it.
+        #[serde(skip)]
+        concealed: bool,
+    },
+    NpcRemoved {
+        token_id: String,
+        /// Whether the token was hidden, in which case players never learn of its removal.
+        #[serde(skip)]
+        hidden: bool,
+    },
+    /// A token's hit points or conditions changed; carries its full status.
+    TokenUpdated {
+        token_id: String,
+        hp: Option<HitPoints>,
+        conditions: Vec<ActiveCondition>,
+        size: TokenSize,
+    },
+    ClientConnected {
+        player_id: ClientId,
+    },
+    ClientDisconnected {
+        player_id: ClientId,
+    },
+    PlayerKicked {
+        player_id: String,
+    },
+    PlayerRemoved {
+        player_id: String,
+    },
+    MovementLocked {
+        locked: bool,
+    },
+    MovementReset,
+    EncounterStarted {
+        restrict_movement: bool,
+    },
+    /// The full initiative order after someone rolled.
+    InitiativeUpdated {
+        order: Vec<InitiativeEntry>,
+    },
+    TurnChanged {
+        player_id: String,
+        round: u32,
+    },
+    EncounterEnded,
+    DiceRolled(Box<DiceRoll>),
+    /// The rolls the recipient may see, oldest first.
+    RollLog {
+        rolls: Vec<DiceRoll>,
+    },
+    ChatMessage(Box<ChatMessage>),
+    /// Recent chat the recipient may read, oldest first, sent after joining.
+    ChatHistory {
+        messages: Vec<ChatMessage>,
+    },
+    /// Ends a resync; the client is now up to date with `version`.
+    ResyncComplete {
+        version: u64,
+    },
+    /// Sent to every client right before the server closes their connection.
+    ServerShutdown,
+    /// Stands in for a state update about a token the recipient may not know
+    /// of, so it sees no gap in versions.
+    Noop,
+    Error {
+        code: ErrorCode,
+        message: String,
+    },
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    /// The frame was not valid JSON or did not match any known message.
+    InvalidMessage,
+    /// A move targeted a cell outside the map.
+    OutOfBounds,
+    /// A move targeted a wall, blocked cell or closed door.
+    Blocked,
+    /// A move targeted a cell another token stands on, or `player_join` found no free cell near the spawn.
+    Occupied,
+    /// No path leads to the move's destination.
+    NoPath,
+    /// The move costs more than the token's remaining movement budget.
+    MovementExceeded,
+    /// A token tried to move out of turn during an encounter that restricts movement.
+    NotYourTurn,
+    /// The command needs an encounter but none is running.
+    NoEncounter,
+    /// `start_encounter` was sent while an encounter is already running.
+    EncounterActive,
+    /// `next_turn` was sent before anyone rolled initiative.
+    InitiativeEmpty,
+    /// `roll` used notation the server could not parse or that exceeds its limits.
+    InvalidDice,
+    /// `update_token` or `create_npc` carried invalid hit points or condition durations.
+    InvalidStatus,
+    /// A chat message was empty, too long or missing its whisper recipient.
+    InvalidChat,
+    /// `set_door` named a cell without a door.
+    NoDoor,
+    /// `player_join` used a name another player already registered.
+    NameTaken,
+    /// `player_join` used a player id that is already registered.
+    PlayerIdTaken,
+    /// `player_reconnect` presented a token that matches no player.
+    InvalidReconnectToken,
+    /// The connection has not joined as a player yet.
+    NotJoined,
+    /// `player_join` or `player_reconnect` came from a connection already bound to a player.
+    AlreadyJoined,
+    /// The connection is not allowed to perform the action.
+    PermissionDenied,
+    /// The message referenced a player that does not exist.
+    UnknownPlayer,
+    /// The message referenced a non-player token that does not exist.
+    UnknownToken,
+    /// A non-game-master tried to move while movement is locked.
+    MovementLocked,
+    /// The game master tried to remove a player who is still connected.
+    PlayerOnline,
+    /// `undo` was sent with no board changes left to revert.
+    NothingToUndo,
+    /// `redo` was sent with no undone changes to reapply.
+    NothingToRedo,
+    /// The connection sent messages faster than its rate limit; they were ignored.
+    RateLimited,
+}
+
+/// A server message stamped with the game state version it reflects.
+///
+/// Every accepted change to a room's game state bumps its version and is
+/// broadcast as one of these, so clients can detect gaps and `resync`.
+#[derive(Debug, Clone, Serialize)]
+pub struct StateUpdate {
+    pub version: u64,
+    #[serde(flatten)]
+    pub message: ServerMessage,
+}
+
+impl ServerMessage {
+    pub fn error(code: ErrorCode, message: impl Into<String>) -> Self {
+        ServerMessage::Error {
+            code,
+            message: message.into(),
+        }
+    }
+}
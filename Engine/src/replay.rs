@@ -0,0 +1,79 @@
+use clap::Args;
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader};
+use std::path::PathBuf;
+use std::process;
+
+use crate::events::{GameEvent, LogEntry};
+use crate::map::MapDefinition;
+use crate::persistence::Snapshot;
+use crate::GameState;
+
+/// Arguments of `warp-drive replay`.
+#[derive(Debug, Args)]
+pub struct ReplayArgs {
+    /// Event log to replay
+    log: PathBuf,
+    /// Only replay this room [default: every room]
+    #[arg(long, value_name = "ID")]
+    room: Option<String>,
+    /// Stop after this many events of the replayed rooms
+    #[arg(long, value_name = "N", conflicts_with = "until")]
+    events: Option<usize>,
+    /// Stop after the last event logged at or before this time, in milliseconds since the Unix epoch
+    #[arg(long, value_name = "TIMESTAMP_MS")]
+    until: Option<u64>,
+    /// Where to write the snapshot [default: stdout]
+    #[arg(long, short, value_name = "FILE")]
+    output: Option<PathBuf>,
+}
+
+/// Runs `warp-drive replay`: rebuilds each room's game state from an event log
+/// and writes it as a snapshot that can be loaded with `STATE_FILE`. Exits the
+/// process if the log cannot be read.
+pub fn run(args: ReplayArgs) {
+    let snapshot = replay(&args).unwrap_or_else(|e| fail(&e));
+    let contents = serde_json::to_string_pretty(&snapshot).unwrap_or_else(|e| fail(&format!("Failed to serialize snapshot: {}", e)));
+    match &args.output {
+        Some(path) => fs::write(path, contents).unwrap_or_else(|e| fail(&format!("Failed to write {}: {}", path.display(), e))),
+        None => println!("{}", contents),
+    }
+}
+
+fn fail(message: &str) -> ! {
+    eprintln!("{}", message);
+    process::exit(1);
+}
+
+fn replay(args: &ReplayArgs) -> Result<Snapshot, String> {
+    let file = File::open(&args.log).map_err(|e| format!("Failed to open {}: {}", args.log.display(), e))?;
+    let mut rooms: HashMap<String, GameState> = HashMap::new();
+    let mut replayed = 0;
+
+    for (index, line) in BufReader::new(file).lines().enumerate() {
+        let line = line.map_err(|e: io::Error| format!("Failed to read {}: {}", args.log.display(), e))?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let entry: LogEntry = serde_json::from_str(&line).map_err(|e| format!("Invalid event on line {}: {}", index + 1, e))?;
+        if args.room.as_ref().is_some_and(|room| *room != entry.room) {
+            continue;
+        }
+        if args.events == Some(replayed) || args.until.is_some_and(|until| entry.timestamp_ms > until) {
+            break;
+        }
+
+        if let GameEvent::RoomClosed = entry.event {
+            rooms.remove(&entry.room);
+        } else {
+            rooms
+                .entry(entry.room)
+                .or_insert_with(|| GameState::new(MapDefinition::default()))
+                .apply(entry.event);
+        }
+        replayed += 1;
+    }
+
+    Ok(Snapshot { rooms })
+}
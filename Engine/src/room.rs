@@ -0,0 +1,193 @@
+use log::info;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::RwLock;
+
+use crate::events::{EventLog, GameEvent, RoomLog};
+use crate::map::MapDefinition;
+use crate::outbound::OutboundQueue;
+use crate::{ClientId, ClientToPlayerMap, Clients, GameState, SharedGameState};
+
+/// Room used by connections to the bare `/ws` endpoint.
+pub const DEFAULT_ROOM_ID: &str = "default";
+
+const MAX_ROOM_ID_LEN: usize = 64;
+
+/// Longest time between two sweeps for idle rooms.
+const IDLE_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+pub type Rooms = Arc<RwLock<HashMap<String, Arc<Room>>>>;
+
+/// An isolated table: its own game state, connected clients and broadcast scope.
+pub struct Room {
+    pub id: String,
+    pub clients: Clients,
+    pub game_state: SharedGameState,
+    pub client_to_player: ClientToPlayerMap,
+    /// When the last client left; `None` while anyone is connected.
+    idle_since: Mutex<Option<Instant>>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct RoomSummary {
+    pub id: String,
+    pub clients: usize,
+    pub players: usize,
+}
+
+impl Room {
+    fn new(id: String, map: MapDefinition, event_log: Option<Arc<EventLog>>) -> Self {
+        let mut game_state = GameState::new(map.clone());
+        if let Some(event_log) = event_log {
+            game_state.attach_event_log(RoomLog::new(id.clone(), event_log));
+            game_state.log_event(GameEvent::RoomCreated { map: Box::new(map) });
+        }
+        Self::with_game_state(id, game_state)
+    }
+
+    pub fn with_game_state(id: String, game_state: GameState) -> Self {
+        Self {
+            id,
+            clients: Arc::new(RwLock::new(HashMap::new())),
+            game_state: Arc::new(RwLock::new(game_state)),
+            client_to_player: Arc::new(RwLock::new(HashMap::new())),
+            idle_since: Mutex::new(Some(Instant::now())),
+        }
+    }
+
+    fn set_idle(&self, idle: bool) {
+        *self.idle_since.lock().unwrap() = idle.then(Instant::now);
+    }
+
+    /// Whether nobody has been connected for at least `timeout`.
+    async fn is_idle(&self, timeout: Duration) -> bool {
+        let idle_since = *self.idle_since.lock().unwrap();
+        idle_since.is_some_and(|since| since.elapsed() >= timeout) && self.clients.read().await.is_empty()
+    }
+
+    async fn close(&self) {
+        self.game_state.read().await.log_event(GameEvent::RoomClosed);
+    }
+
+    /// A room is empty once nobody is connected and no players are registered.
+    async fn is_empty(&self) -> bool {
+        // Never hold the clients lock while waiting on the game state lock:
+        // handlers broadcast to clients while holding the game state lock
+        let no_clients = self.clients.read().await.is_empty();
+        no_clients && self.game_state.read().await.get_all_player_info().is_empty()
+    }
+
+    async fn summary(&self) -> RoomSummary {
+        let clients = self.clients.read().await.len();
+        let players = self.game_state.read().await.get_all_player_info().len();
+        RoomSummary {
+            id: self.id.clone(),
+            clients,
+            players,
+        }
+    }
+}
+
+/// Room ids appear in URLs, so keep them short and URL-safe.
+pub fn is_valid_room_id(room_id: &str) -> bool {
+    !room_id.is_empty()
+        && room_id.len() <= MAX_ROOM_ID_LEN
+        && room_id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+/// Whether a client could join `room_id`: it exists, or fewer than `max_rooms` do.
+pub async fn can_join(rooms: &Rooms, room_id: &str, max_rooms: usize) -> bool {
+    let rooms_lock = rooms.read().await;
+    rooms_lock.contains_key(room_id) || rooms_lock.len() < max_rooms
+}
+
+/// Registers a client in `room_id`, creating the room on `map` if it does not exist yet.
+/// A new room writes its changes to `event_log`. Returns `None` if the room would
+/// have to be created but `max_rooms` are already open.
+///
+/// The registry lock is held while the client is inserted so that a concurrent
+/// teardown cannot remove the room between lookup and registration.
+pub async fn join_room(
+    rooms: &Rooms,
+    room_id: &str,
+    client_id: &ClientId,
+    queue: Arc<OutboundQueue>,
+    map: &MapDefinition,
+    event_log: Option<Arc<EventLog>>,
+    max_rooms: usize,
+) -> Option<Arc<Room>> {
+    let mut rooms_lock = rooms.write().await;
+    if !rooms_lock.contains_key(room_id) && rooms_lock.len() >= max_rooms {
+        return None;
+    }
+    let room = rooms_lock
+        .entry(room_id.to_string())
+        .or_insert_with(|| {
+            info!("Creating room {}", room_id);
+            Arc::new(Room::new(room_id.to_string(), map.clone(), event_log))
+        })
+        .clone();
+
+    let mut clients_lock = room.clients.write().await;
+    clients_lock.insert(client_id.clone(), queue);
+    room.set_idle(false);
+    info!("Client {} joined room {}. Clients in room: {}", client_id, room_id, clients_lock.len());
+    drop(clients_lock);
+
+    Some(room)
+}
+
+/// Removes a client from its room and tears the room down if it is now empty.
+pub async fn leave_room(rooms: &Rooms, room: &Room, client_id: &str) {
+    let mut rooms_lock = rooms.write().await;
+
+    {
+        let mut clients_lock = room.clients.write().await;
+        clients_lock.remove(client_id);
+        room.set_idle(clients_lock.is_empty());
+        info!("Client {} left room {}. Clients in room: {}", client_id, room.id, clients_lock.len());
+    }
+
+    if room.is_empty().await {
+        room.close().await;
+        rooms_lock.remove(&room.id);
+        info!("Tearing down empty room {}. Active rooms: {}", room.id, rooms_lock.len());
+    }
+}
+
+/// Periodically tears down rooms nobody has been connected to for `idle_timeout`,
+/// along with the players registered in them, so abandoned rooms do not pile up.
+pub async fn run_idle_room_task(rooms: Rooms, idle_timeout: Duration) {
+    let mut interval = tokio::time::interval(IDLE_CHECK_INTERVAL.min(idle_timeout));
+    loop {
+        interval.tick().await;
+        close_idle_rooms(&rooms, idle_timeout).await;
+    }
+}
+
+async fn close_idle_rooms(rooms: &Rooms, idle_timeout: Duration) {
+    let mut rooms_lock = rooms.write().await;
+    let mut idle = Vec::new();
+    for room in rooms_lock.values() {
+        if room.is_idle(idle_timeout).await {
+            idle.push(room.clone());
+        }
+    }
+    for room in idle {
+        room.close().await;
+        rooms_lock.remove(&room.id);
+        info!("Tearing down idle room {}. Active rooms: {}", room.id, rooms_lock.len());
+    }
+}
+
+pub async fn list_rooms(rooms: &Rooms) -> Vec<RoomSummary> {
+    let rooms_lock = rooms.read().await;
+    let mut summaries = Vec::with_capacity(rooms_lock.len());
+    for room in rooms_lock.values() {
+        summaries.push(room.summary().await);
+    }
+    summaries.sort_by(|a, b| a.id.cmp(&b.id));
+    summaries
+}
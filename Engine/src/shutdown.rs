@@ -0,0 +1,93 @@
+use log::{error, info, warn};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::Instant;
+use warp::ws::Message;
+
+use crate::protocol::ServerMessage;
+use crate::room::{Room, Rooms};
+use crate::{broadcast_server_message_to_all, ClientId};
+
+/// Close code for connections ended by a server shutdown ("going away").
+const CLOSE_CODE_GOING_AWAY: u16 = 1001;
+
+const DRAIN_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Set once shutdown begins so new WebSocket upgrades are refused.
+pub type ShutdownFlag = Arc<AtomicBool>;
+
+pub fn is_shutting_down(flag: &ShutdownFlag) -> bool {
+    flag.load(Ordering::SeqCst)
+}
+
+/// Resolves on Ctrl+C or SIGTERM and raises `flag`.
+pub async fn wait_for_signal(flag: ShutdownFlag) {
+    let ctrl_c = async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            error!("Failed to listen for Ctrl+C: {}", e);
+            std::future::pending::<()>().await;
+        }
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut signal) => {
+                signal.recv().await;
+            }
+            Err(e) => {
+                error!("Failed to listen for SIGTERM: {}", e);
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => info!("Received Ctrl+C"),
+        _ = terminate => info!("Received SIGTERM"),
+    }
+    flag.store(true, Ordering::SeqCst);
+}
+
+/// Tells every client the server is going away, sends each a close frame and
+/// waits until they have all disconnected or `deadline` has passed.
+pub async fn disconnect_all_clients(rooms: &Rooms, deadline: Duration) {
+    let started = Instant::now();
+    let room_list: Vec<_> = rooms.read().await.values().cloned().collect();
+
+    for room in &room_list {
+        broadcast_server_message_to_all(&room.clients, &ServerMessage::ServerShutdown).await;
+
+        let clients_lock = room.clients.read().await;
+        for (client_id, queue) in clients_lock.iter() {
+            if let Err(e) = queue.push(Message::close_with(CLOSE_CODE_GOING_AWAY, "Server shutting down")) {
+                info!("Could not queue close frame for client {}: {}", client_id, e);
+            }
+        }
+    }
+
+    loop {
+        let remaining = connected_clients(&room_list).await;
+        if remaining.is_empty() {
+            info!("All clients disconnected");
+            return;
+        }
+        if started.elapsed() >= deadline {
+            warn!("Shutdown deadline reached with {} clients still connected", remaining.len());
+            return;
+        }
+        tokio::time::sleep(DRAIN_POLL_INTERVAL).await;
+    }
+}
+
+async fn connected_clients(room_list: &[Arc<Room>]) -> Vec<ClientId> {
+    let mut client_ids = Vec::new();
+    for room in room_list {
+        client_ids.extend(room.clients.read().await.keys().cloned());
+    }
+    client_ids
+}
@@ -0,0 +1,208 @@
+use serde::{Deserialize, Serialize};
+
+use crate::Position;
+
+/// A token's size category. Its footprint is a square of cells whose
+/// top-left corner is the token's position.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TokenSize {
+    /// 1x1
+    Tiny,
+    /// 1x1
+    Small,
+    /// 1x1
+    #[default]
+    Medium,
+    /// 2x2
+    Large,
+    /// 3x3
+    Huge,
+    /// 4x4
+    Gargantuan,
+}
+
+impl TokenSize {
+    /// Width and height of the footprint in cells.
+    pub fn span(self) -> i32 {
+        match self {
+            TokenSize::Tiny | TokenSize::Small | TokenSize::Medium => 1,
+            TokenSize::Large => 2,
+            TokenSize::Huge => 3,
+            TokenSize::Gargantuan => 4,
+        }
+    }
+
+    /// The cells covered by a token of this size standing at `position`.
+    pub fn footprint(self, position: Position) -> impl Iterator<Item = Position> {
+        let span = self.span();
+        (0..span).flat_map(move |dy| {
+            (0..span).map(move |dx| Position {
+                x: position.x + dx,
+                y: position.y + dy,
+            })
+        })
+    }
+}
+
+/// Which side a token is on. Tokens on the same side are allies.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Faction {
+    /// The players' side; every player token belongs to it.
+    Party,
+    #[default]
+    Hostile,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct HitPoints {
+    pub current: i32,
+    pub max: i32,
+    /// Temporary hit points, lost before `current`.
+    #[serde(default)]
+    pub temp: i32,
+}
+
+impl HitPoints {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.max < 0 {
+            return Err("max hit points cannot be negative".to_string());
+        }
+        if self.current > self.max {
+            return Err(format!("current hit points ({}) cannot exceed max ({})", self.current, self.max));
+        }
+        if self.temp < 0 {
+            return Err("temporary hit points cannot be negative".to_string());
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Condition {
+    Blinded,
+    Charmed,
+    Concentrating,
+    Deafened,
+    Frightened,
+    Grappled,
+    Incapacitated,
+    Invisible,
+    Paralyzed,
+    Petrified,
+    Poisoned,
+    Prone,
+    Restrained,
+    Stunned,
+    Unconscious,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct ActiveCondition {
+    pub condition: Condition,
+    /// Rounds left, counted down at the start of each of the token's turns;
+    /// the condition ends when it reaches zero. `None` lasts until removed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rounds: Option<u32>,
+}
+
+/// Hit points and conditions, tracked the same way for players and non-player tokens.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct TokenStatus {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hp: Option<HitPoints>,
+    /// At most one entry per condition.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub conditions: Vec<ActiveCondition>,
+}
+
+impl TokenStatus {
+    /// Adds a condition, replacing its duration if the token already has it.
+    pub fn add_condition(&mut self, active: ActiveCondition) {
+        self.remove_condition(active.condition);
+        self.conditions.push(active);
+    }
+
+    pub fn remove_condition(&mut self, condition: Condition) {
+        self.conditions.retain(|active| active.condition != condition);
+    }
+
+    /// Counts timed conditions down by a round at the start of the token's
+    /// turn, ending those that run out. Returns whether anything changed.
+    pub fn tick(&mut self) -> bool {
+        let mut changed = false;
+        self.conditions.retain_mut(|active| match active.rounds.as_mut() {
+            Some(rounds) => {
+                changed = true;
+                *rounds = rounds.saturating_sub(1);
+                *rounds > 0
+            }
+            None => true,
+        });
+        changed
+    }
+}
+
+/// A token the game master places and controls, such as an NPC, a monster or
+/// an object. Unlike players it is not tied to a connection.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NpcToken {
+    pub name: String,
+    pub color: String,
+    /// Optional image for clients to draw instead of a colored disc.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub icon: Option<String>,
+    #[serde(default)]
+    pub size: TokenSize,
+    #[serde(default)]
+    pub faction: Faction,
+    pub position: Position,
+    /// Hidden tokens are only shown to the game master.
+    #[serde(default)]
+    pub hidden: bool,
+    #[serde(flatten)]
+    pub status: TokenStatus,
+}
+
+/// The fields of an `update_npc`; anything left out stays as it is. Hit points
+/// and conditions are changed with `update_token` instead.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct NpcChanges {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub color: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub icon: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub size: Option<TokenSize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub faction: Option<Faction>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hidden: Option<bool>,
+}
+
+impl NpcToken {
+    pub fn apply(&mut self, changes: NpcChanges) {
+        if let Some(name) = changes.name {
+            self.name = name;
+        }
+        if let Some(color) = changes.color {
+            self.color = color;
+        }
+        if let Some(icon) = changes.icon {
+            self.icon = Some(icon);
+        }
+        if let Some(size) = changes.size {
+            self.size = size;
+        }
+        if let Some(faction) = changes.faction {
+            self.faction = faction;
+        }
+        if let Some(hidden) = changes.hidden {
+            self.hidden = hidden;
+        }
+    }
+}
@@ -0,0 +1,451 @@
+use crate::map::MapDefinition;
+use crate::protocol::{ServerMessage, StateUpdate};
+use crate::{ClientSession, GameState, Position};
+
+/// Whose point of view a message is being filtered for.
+#[derive(Debug, Clone)]
+pub enum Viewer {
+    /// Game masters see every token, hidden ones included.
+    Everything,
+    /// Without fog of war, everyone else sees every token that is not hidden.
+    Revealed,
+    /// Under fog of war, a player sees their own token and whatever it has line of sight to.
+    Player { player_id: String, position: Position },
+    /// Under fog of war, connections that have not joined see no tokens.
+    Nothing,
+}
+
+impl Viewer {
+    pub fn for_session(session: Option<&ClientSession>, state: &GameState, fog_of_war: bool) -> Self {
+        match session {
+            Some(session) if session.is_game_master() => Viewer::Everything,
+            _ if !fog_of_war => Viewer::Revealed,
+            Some(session) => match state.get_player_position(&session.player_id) {
+                Some(position) => Viewer::Player {
+                    player_id: session.player_id.clone(),
+                    position: *position,
+                },
+                None => Viewer::Nothing,
+            },
+            None => Viewer::Nothing,
+        }
+    }
+
+    fn can_see(&self, state: &GameState, player_id: &str, position: Position, sight_radius: i32) -> bool {
+        match self {
+            Viewer::Everything => true,
+            _ if state.is_hidden(player_id) => false,
+            Viewer::Revealed => true,
+            Viewer::Player { player_id: own_id, position: own_position } => {
+                // A large token is in sight if any cell it covers is
+                own_id == player_id
+                    || state
+                        .token_size(player_id)
+                        .footprint(position)
+                        .any(|cell| has_line_of_sight(state.map(), *own_position, cell, sight_radius))
+            }
+            Viewer::Nothing => false,
+        }
+    }
+
+    fn is_player(&self, player_id: &str) -> bool {
+        matches!(self, Viewer::Player { player_id: own_id, .. } if own_id == player_id)
+    }
+}
+
+/// Returns true if `to` is within `sight_radius` cells of `from` and no wall
+/// lies on the line between them. Walls themselves can be seen.
+pub fn has_line_of_sight(map: &MapDefinition, from: Position, to: Position, sight_radius: i32) -> bool {
+    let dx = i64::from(to.x - from.x);
+    let dy = i64::from(to.y - from.y);
+    let radius = i64::from(sight_radius);
+    if dx * dx + dy * dy > radius * radius {
+        return false;
+    }
+
+    line_between(from, to)
+        .into_iter()
+        .filter(|cell| *cell != from && *cell != to)
+        .all(|cell| !map.blocks_sight(&cell))
+}
+
+/// Cells on the Bresenham line from `from` to `to`, both included.
+fn line_between(from: Position, to: Position) -> Vec<Position> {
+    let dx = (to.x - from.x).abs();
+    let dy = -(to.y - from.y).abs();
+    let step_x = if from.x < to.x { 1 } else { -1 };
+    let step_y = if from.y < to.y { 1 } else { -1 };
+
+    let mut cells = Vec::new();
+    let mut current = from;
+    let mut error = dx + dy;
+    loop {
+        cells.push(current);
+        if current == to {
+            return cells;
+        }
+        let doubled = 2 * error;
+        if doubled >= dy {
+            error += dy;
+            current.x += step_x;
+        }
+        if doubled <= dx {
+            error += dx;
+            current.y += step_y;
+        }
+    }
+}
+
+/// Rewrites `message` so it only reveals the tokens `viewer` can currently see.
+///
+/// Moves out of sight become `token_hidden`.
+/// Hidden tokens are left out altogether, and updates only about them become `noop`.
+pub fn filter_message(state: &GameState, viewer: &Viewer, message: &ServerMessage, sight_radius: i32) -> ServerMessage {
+    if let Viewer::Everything = viewer {
+        return message.clone();
+    }
+
+    match message {
+        ServerMessage::NpcCreated { token_id, .. }
+        | ServerMessage::NpcUpdated { token_id, concealed: false, .. }
+        | ServerMessage::PlayerMove { player_id: token_id, .. }
+        | ServerMessage::TokenUpdated { token_id, .. }
+        | ServerMessage::TurnChanged { player_id: token_id, .. }
+            if state.is_hidden(token_id) =>
+        {
+            ServerMessage::Noop
+        }
+        ServerMessage::NpcRemoved { hidden: true, .. } => ServerMessage::Noop,
+        ServerMessage::NpcUpdated { token_id, concealed: true, .. } => ServerMessage::TokenHidden {
+            player_id: token_id.clone(),
+        },
+        ServerMessage::InitiativeUpdated { order } => ServerMessage::InitiativeUpdated {
+            order: order.iter().filter(|entry| !state.is_hidden(&entry.player_id)).cloned().collect(),
+        },
+        ServerMessage::GameState { data, movement_locked, encounter, npcs } => ServerMessage::GameState {
+            data: data
+                .iter()
+                .filter(|(player_id, player_info)| viewer.can_see(state, player_id, player_info.position, sight_radius))
+                .map(|(player_id, player_info)| (player_id.clone(), player_info.clone()))
+                .collect(),
+            movement_locked: *movement_locked,
+            encounter: encounter.as_ref().map(|encounter| {
+                let mut encounter = encounter.clone();
+                encounter.order.retain(|entry| !state.is_hidden(&entry.player_id));
+                encounter.active = encounter.active.filter(|token_id| !state.is_hidden(token_id));
+                encounter
+            }),
+            npcs: npcs
+                .iter()
+                .filter(|(token_id, token)| viewer.can_see(state, token_id, token.position, sight_radius))
+                .map(|(token_id, token)| (token_id.clone(), token.clone()))
+                .collect(),
+        },
+        ServerMessage::PositionsUpdate { data } => ServerMessage::PositionsUpdate {
+            data: data
+                .iter()
+                .filter(|(player_id, position)| viewer.can_see(state, player_id, **position, sight_radius))
+                .map(|(player_id, position)| (player_id.clone(), *position))
+                .collect(),
+        },
+        // A token created out of sight has never been seen
+        ServerMessage::NpcCreated { token_id, token } if !viewer.can_see(state, token_id, token.position, sight_radius) => ServerMessage::Noop,
+        ServerMessage::NpcUpdated { token_id, token, .. } if !viewer.can_see(state, token_id, token.position, sight_radius) => {
+            ServerMessage::TokenHidden {
+                player_id: token_id.clone(),
+            }
+        }
+        ServerMessage::PlayerMove { player_id, position, path, cost, .. } => {
+            if viewer.can_see(state, player_id, *position, sight_radius) {
+                // The token may be coming into view, so say who it is, and only
+                // show the path if none of it passes through unseen cells
+                let (player_name, color) = state.token_label(player_id).unzip();
+                let path = path.as_ref().filter(|path| {
+                    path.iter().all(|cell| viewer.can_see(state, player_id, *cell, sight_radius))
+                });
+                ServerMessage::PlayerMove {
+                    player_id: player_id.clone(),
+                    player_name,
+                    color,
+                    position: *position,
+                    path: path.cloned(),
+                    cost: path.and(*cost),
+                }
+            } else {
+                ServerMessage::TokenHidden {
+                    player_id: player_id.clone(),
+                }
+            }
+        }
+        ServerMessage::TokenUpdated { token_id, .. }
+            if state
+                .get_player_position(token_id)
+                .is_some_and(|position| !viewer.can_see(state, token_id, *position, sight_radius)) =>
+        {
+            ServerMessage::TokenHidden {
+                player_id: token_id.clone(),
+            }
+        }
+        ServerMessage::PlayerJoin { player_id, player_name, color, size, position: Some(position) }
+            if !viewer.can_see(state, player_id, *position, sight_radius) =>
+        {
+            ServerMessage::PlayerJoin {
+                player_id: player_id.clone(),
+                player_name: player_name.clone(),
+                color: color.clone(),
+                size: *size,
+                position: None,
+            }
+        }
+        other => other.clone(),
+    }
+}
+
+/// Whether `message` could reveal more than some recipient may see. The
+/// removal of a hidden token needs filtering even once no token is hidden.
+pub fn needs_filtering(state: &GameState, fog_of_war: bool, message: &ServerMessage) -> bool {
+    fog_of_war || state.has_hidden_tokens() || matches!(message, ServerMessage::NpcRemoved { hidden: true, .. })
+}
+
+/// Filters `update` for `viewer`. A viewer's own move changes what it can see,
+/// so under fog of war it is followed by a filtered `game_state` of the same version.
+pub fn filter_update(state: &GameState, viewer: &Viewer, update: &StateUpdate, sight_radius: i32) -> Vec<StateUpdate> {
+    let stamp = |message| StateUpdate {
+        version: update.version,
+        message,
+    };
+    let mut filtered = vec![stamp(filter_message(state, viewer, &update.message, sight_radius))];
+    if let ServerMessage::PlayerMove { player_id, .. } = &update.message {
+        if viewer.is_player(player_id) {
+            filtered.push(stamp(filter_message(state, viewer, &state.game_state_message(), sight_radius)));
+        }
+    }
+    filtered
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::initiative::InitiativeEntry;
+    use crate::map::Door;
+    use crate::token::{NpcToken, TokenSize};
+
+    const SIGHT: i32 = 8;
+
+    fn at(x: i32, y: i32) -> Position {
+        Position { x, y }
+    }
+
+    fn npc(position: Position, hidden: bool) -> NpcToken {
+        NpcToken {
+            name: "Goblin".to_string(),
+            color: "#00ff00".to_string(),
+            icon: None,
+            size: TokenSize::Medium,
+            faction: Default::default(),
+            position,
+            hidden,
+            status: Default::default(),
+        }
+    }
+
+    /// A 20x10 room with a wall from (5, 0) to (5, 9), a player at (1, 1) and
+    /// a visible and a hidden goblin on the player's side of the wall.
+    fn state() -> GameState {
+        let mut map = MapDefinition::new(20, 10);
+        map.walls.extend((0..10).map(|y| at(5, y)));
+        let mut state = GameState::new(map);
+        state.add_player_info("p1".to_string(), "Aria".to_string(), "#ff0000".to_string(), TokenSize::Medium, at(1, 1));
+        state.add_npc("goblin".to_string(), npc(at(3, 3), false));
+        state.add_npc("lurker".to_string(), npc(at(2, 2), true));
+        state
+    }
+
+    fn player_at(position: Position) -> Viewer {
+        Viewer::Player {
+            player_id: "p1".to_string(),
+            position,
+        }
+    }
+
+    fn move_of(player_id: &str, position: Position, path: Vec<Position>) -> ServerMessage {
+        ServerMessage::PlayerMove {
+            player_id: player_id.to_string(),
+            player_name: None,
+            color: None,
+            position,
+            cost: Some(path.len() as u32),
+            path: Some(path),
+        }
+    }
+
+    #[test]
+    fn sees_within_the_sight_radius() {
+        let map = MapDefinition::new(20, 20);
+        assert!(has_line_of_sight(&map, at(0, 0), at(8, 0), 8));
+        assert!(has_line_of_sight(&map, at(0, 0), at(5, 5), 8));
+        assert!(!has_line_of_sight(&map, at(0, 0), at(9, 0), 8));
+        assert!(!has_line_of_sight(&map, at(0, 0), at(6, 6), 8));
+    }
+
+    #[test]
+    fn walls_block_sight_but_can_be_seen() {
+        let mut map = MapDefinition::new(20, 20);
+        map.walls.insert(at(2, 0));
+        assert!(!has_line_of_sight(&map, at(0, 0), at(4, 0), 8));
+        assert!(has_line_of_sight(&map, at(0, 0), at(2, 0), 8));
+        assert!(has_line_of_sight(&map, at(0, 0), at(4, 2), 8));
+    }
+
+    #[test]
+    fn closed_doors_block_sight_and_open_ones_do_not() {
+        let mut map = MapDefinition::new(20, 20);
+        map.doors.push(Door { position: at(2, 0), open: false });
+        assert!(!has_line_of_sight(&map, at(0, 0), at(4, 0), 8));
+        map.set_door(&at(2, 0), true);
+        assert!(has_line_of_sight(&map, at(0, 0), at(4, 0), 8));
+    }
+
+    #[test]
+    fn blocked_cells_do_not_block_sight() {
+        let mut map = MapDefinition::new(20, 20);
+        map.blocked.insert(at(2, 0));
+        assert!(has_line_of_sight(&map, at(0, 0), at(4, 0), 8));
+    }
+
+    #[test]
+    fn line_between_includes_both_ends() {
+        assert_eq!(line_between(at(0, 0), at(3, 0)), vec![at(0, 0), at(1, 0), at(2, 0), at(3, 0)]);
+        assert_eq!(line_between(at(2, 2), at(0, 0)), vec![at(2, 2), at(1, 1), at(0, 0)]);
+        assert_eq!(line_between(at(1, 1), at(1, 1)), vec![at(1, 1)]);
+    }
+
+    #[test]
+    fn game_masters_see_hidden_tokens() {
+        let state = state();
+        let message = filter_message(&state, &Viewer::Everything, &move_of("lurker", at(2, 3), vec![at(2, 3)]), SIGHT);
+        assert!(matches!(message, ServerMessage::PlayerMove { player_id, .. } if player_id == "lurker"));
+    }
+
+    #[test]
+    fn updates_about_hidden_tokens_become_noops() {
+        let state = state();
+        let message = filter_message(&state, &Viewer::Revealed, &move_of("lurker", at(2, 3), vec![at(2, 3)]), SIGHT);
+        assert!(matches!(message, ServerMessage::Noop));
+
+        let removed = ServerMessage::NpcRemoved {
+            token_id: "gone".to_string(),
+            hidden: true,
+        };
+        assert!(matches!(filter_message(&state, &Viewer::Revealed, &removed, SIGHT), ServerMessage::Noop));
+    }
+
+    #[test]
+    fn concealing_a_token_hides_it() {
+        let state = state();
+        let concealed = ServerMessage::NpcUpdated {
+            token_id: "lurker".to_string(),
+            token: Box::new(npc(at(2, 2), true)),
+            concealed: true,
+        };
+        let message = filter_message(&state, &Viewer::Revealed, &concealed, SIGHT);
+        assert!(matches!(message, ServerMessage::TokenHidden { player_id } if player_id == "lurker"));
+    }
+
+    #[test]
+    fn moves_out_of_sight_become_token_hidden() {
+        let state = state();
+        let message = filter_message(&state, &player_at(at(1, 1)), &move_of("goblin", at(7, 3), vec![at(4, 3), at(5, 3), at(6, 3), at(7, 3)]), SIGHT);
+        assert!(matches!(message, ServerMessage::TokenHidden { player_id } if player_id == "goblin"));
+    }
+
+    #[test]
+    fn moves_in_sight_name_the_token() {
+        let state = state();
+        let message = filter_message(&state, &player_at(at(1, 1)), &move_of("goblin", at(3, 2), vec![at(3, 2)]), SIGHT);
+        let ServerMessage::PlayerMove { player_name, path, cost, .. } = message else {
+            panic!("expected a player_move, got {:?}", message);
+        };
+        assert_eq!(player_name.as_deref(), Some("Goblin"));
+        assert_eq!(path, Some(vec![at(3, 2)]));
+        assert_eq!(cost, Some(1));
+    }
+
+    #[test]
+    fn paths_through_unseen_cells_are_left_out() {
+        let state = state();
+        // The path starts behind the wall, out of the player's sight
+        let path = vec![at(6, 3), at(4, 3)];
+        let message = filter_message(&state, &player_at(at(1, 1)), &move_of("goblin", at(4, 3), path), SIGHT);
+        let ServerMessage::PlayerMove { path, cost, .. } = message else {
+            panic!("expected a player_move, got {:?}", message);
+        };
+        assert_eq!(path, None);
+        assert_eq!(cost, None);
+    }
+
+    #[test]
+    fn game_state_leaves_out_hidden_and_unseen_tokens() {
+        let mut state = state();
+        state.add_npc("guard".to_string(), npc(at(8, 1), false));
+        let message = filter_message(&state, &player_at(at(1, 1)), &state.game_state_message(), SIGHT);
+        let ServerMessage::GameState { data, npcs, .. } = message else {
+            panic!("expected a game_state, got {:?}", message);
+        };
+        assert!(data.contains_key("p1"));
+        assert!(npcs.contains_key("goblin"));
+        assert!(!npcs.contains_key("lurker"));
+        assert!(!npcs.contains_key("guard"));
+    }
+
+    #[test]
+    fn without_fog_only_hidden_tokens_are_left_out() {
+        let mut state = state();
+        state.add_npc("guard".to_string(), npc(at(8, 1), false));
+        let message = filter_message(&state, &Viewer::Revealed, &state.game_state_message(), SIGHT);
+        let ServerMessage::GameState { npcs, .. } = message else {
+            panic!("expected a game_state, got {:?}", message);
+        };
+        assert!(npcs.contains_key("guard"));
+        assert!(!npcs.contains_key("lurker"));
+    }
+
+    #[test]
+    fn initiative_order_leaves_out_hidden_tokens() {
+        let state = state();
+        let order = ["p1", "lurker", "goblin"]
+            .iter()
+            .map(|player_id| InitiativeEntry {
+                player_id: player_id.to_string(),
+                initiative: 10,
+            })
+            .collect();
+        let message = filter_message(&state, &Viewer::Revealed, &ServerMessage::InitiativeUpdated { order }, SIGHT);
+        let ServerMessage::InitiativeUpdated { order } = message else {
+            panic!("expected an initiative_updated, got {:?}", message);
+        };
+        let ids: Vec<&str> = order.iter().map(|entry| entry.player_id.as_str()).collect();
+        assert_eq!(ids, ["p1", "goblin"]);
+    }
+
+    #[test]
+    fn joins_out_of_sight_leave_out_the_position() {
+        let state = state();
+        let join = ServerMessage::PlayerJoin {
+            player_id: "p2".to_string(),
+            player_name: "Bram".to_string(),
+            color: "#0000ff".to_string(),
+            size: TokenSize::Medium,
+            position: Some(at(8, 1)),
+        };
+        let message = filter_message(&state, &player_at(at(1, 1)), &join, SIGHT);
+        assert!(matches!(message, ServerMessage::PlayerJoin { position: None, .. }));
+    }
+
+    #[test]
+    fn unjoined_connections_see_no_tokens_under_fog() {
+        let state = state();
+        let message = filter_message(&state, &Viewer::Nothing, &move_of("goblin", at(3, 2), vec![at(3, 2)]), SIGHT);
+        assert!(matches!(message, ServerMessage::TokenHidden { .. }));
+    }
+}